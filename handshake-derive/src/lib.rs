@@ -0,0 +1,295 @@
+//! `#[derive(Encode, Decode)]` for the wire types of the `handshake` crate.
+//!
+//! Fields are written in declaration order with their own `Encode`/`Decode` impls. A `#[wire]`
+//! attribute changes how a single field is handled:
+//!
+//! - `#[wire(big_endian)]` writes an integer in network byte order, like ports.
+//! - `#[wire(var_int)]` writes a `Vec` behind its compact-size count, as its own impl does, but
+//!   `#[wire(var_int, max = N)]` refuses to decode more than `N` items instead of `MAX_SIZE`.
+//! - `#[wire(since(version = 70001))]` only keeps the field on the wire when the earlier
+//!   `version` field is at least 70001, it decodes to `Default::default()` otherwise.
+//!
+//! Fixed size arrays are written without a length prefix, element by element unless they hold
+//! bytes.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, spanned::Spanned, Data, DeriveInput, Expr, Field, Fields,
+    Generics, Ident, Index, LitStr, Member, Type, TypeArray,
+};
+
+#[proc_macro_derive(Encode, attributes(wire))]
+pub fn derive_encode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_encode(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(Decode, attributes(wire))]
+pub fn derive_decode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_decode(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[derive(Default)]
+struct WireAttrs {
+    big_endian: bool,
+    var_int: bool,
+    max: Option<Expr>,
+    since: Option<(Ident, Expr)>,
+}
+
+impl WireAttrs {
+    fn parse(field: &Field) -> syn::Result<Self> {
+        let mut attrs = Self::default();
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("wire"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("big_endian") {
+                    attrs.big_endian = true;
+                } else if meta.path.is_ident("var_int") {
+                    attrs.var_int = true;
+                } else if meta.path.is_ident("max") {
+                    attrs.max = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("since") {
+                    meta.parse_nested_meta(|inner| {
+                        let Some(ident) = inner.path.get_ident().cloned() else {
+                            return Err(inner.error("expected the name of a version field"));
+                        };
+                        attrs.since = Some((ident, inner.value()?.parse()?));
+                        Ok(())
+                    })?;
+                } else {
+                    return Err(meta.error("expected big_endian, var_int, max or since"));
+                }
+                Ok(())
+            })?;
+        }
+        if attrs.big_endian && attrs.var_int {
+            return Err(syn::Error::new(
+                field.span(),
+                "big_endian and var_int exclude each other",
+            ));
+        }
+        if attrs.max.is_some() && !attrs.var_int {
+            return Err(syn::Error::new(field.span(), "max needs var_int"));
+        }
+        Ok(attrs)
+    }
+}
+
+/// A field along with the expression to reach it from `self` and its local name in `decode`.
+struct WireField<'a> {
+    field: &'a Field,
+    member: Member,
+    local: Ident,
+    what: LitStr,
+    attrs: WireAttrs,
+}
+
+fn wire_fields(input: &DeriveInput) -> syn::Result<Vec<WireField<'_>>> {
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new(
+            input.span(),
+            "Encode and Decode can only be derived for structs",
+        ));
+    };
+    let fields = data.fields.iter().enumerate().map(|(i, field)| {
+        let (member, local) = match &field.ident {
+            Some(ident) => (Member::Named(ident.clone()), ident.clone()),
+            None => (Member::Unnamed(Index::from(i)), format_ident!("__field{i}")),
+        };
+        let name = match &member {
+            Member::Named(ident) => ident.to_string(),
+            Member::Unnamed(index) => index.index.to_string(),
+        };
+        let what = LitStr::new(&format!("{}.{name}", input.ident), field.span());
+        Ok(WireField {
+            field,
+            member,
+            local,
+            what,
+            attrs: WireAttrs::parse(field)?,
+        })
+    });
+    let fields = fields.collect::<syn::Result<Vec<_>>>()?;
+
+    // `since` compares against a field decoded before, so it has to come first
+    for (i, wire) in fields.iter().enumerate() {
+        if let Some((version, _)) = &wire.attrs.since {
+            let earlier = fields[..i].iter().any(|other| other.local == *version);
+            if !earlier {
+                return Err(syn::Error::new(
+                    version.span(),
+                    "since needs a version field declared earlier",
+                ));
+            }
+        }
+    }
+    Ok(fields)
+}
+
+/// Arrays other than `[u8; N]`, which has its own impls, are handled one item at a time.
+fn element_wise(ty: &Type) -> Option<&TypeArray> {
+    match ty {
+        Type::Array(array) if !matches!(&*array.elem, Type::Path(path) if path.path.is_ident("u8")) => {
+            Some(array)
+        }
+        _ => None,
+    }
+}
+
+fn bounded(generics: &Generics, bound: TokenStream2) -> Generics {
+    let mut generics = generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(#bound));
+    }
+    generics
+}
+
+fn expand_encode(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = wire_fields(&input)?;
+    let generics = bounded(&input.generics, quote!(::handshake::bitcoin::Encode));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let name = &input.ident;
+
+    let steps = fields.iter().map(|wire| {
+        let member = &wire.member;
+        let encode = if wire.attrs.big_endian {
+            quote! {
+                let bytes = self.#member.to_be_bytes();
+                ::handshake::bitcoin::__private::bytes::BufMut::put_slice(__buffer, &bytes);
+                written += bytes.len();
+            }
+        } else if wire.attrs.var_int {
+            quote! {
+                written += ::handshake::bitcoin::encode::encode_list(&self.#member, __buffer);
+            }
+        } else if element_wise(&wire.field.ty).is_some() {
+            quote! {
+                for item in self.#member.iter() {
+                    written += ::handshake::bitcoin::Encode::encode(item, __buffer);
+                }
+            }
+        } else {
+            quote! {
+                written += ::handshake::bitcoin::Encode::encode(&self.#member, __buffer);
+            }
+        };
+        match &wire.attrs.since {
+            Some((version, min)) => quote! {
+                if self.#version >= #min {
+                    #encode
+                }
+            },
+            None => quote! { { #encode } },
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics ::handshake::bitcoin::Encode for #name #ty_generics #where_clause {
+            fn encode(
+                &self,
+                __buffer: &mut ::handshake::bitcoin::__private::bytes::BytesMut,
+            ) -> usize {
+                #[allow(unused_mut)]
+                let mut written = 0;
+                #(#steps)*
+                written
+            }
+        }
+    })
+}
+
+fn expand_decode(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = wire_fields(&input)?;
+    let generics = bounded(&input.generics, quote!(::handshake::bitcoin::Decode));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let name = &input.ident;
+    let bytes = quote!(::handshake::bitcoin::__private::bytes);
+
+    let steps = fields.iter().map(|wire| {
+        let ty = &wire.field.ty;
+        let what = &wire.what;
+        let not_enough = quote! {
+            return ::core::result::Result::Err(::handshake::bitcoin::Error::NotEnoughBytes(#what))
+        };
+        let decode = if wire.attrs.big_endian {
+            quote! {{
+                let mut raw = [0; ::core::mem::size_of::<#ty>()];
+                if #bytes::Buf::remaining(__bytes) < raw.len() {
+                    #not_enough;
+                }
+                #bytes::Buf::copy_to_slice(__bytes, &mut raw);
+                <#ty>::from_be_bytes(raw)
+            }}
+        } else if wire.attrs.var_int {
+            let max = match &wire.attrs.max {
+                Some(max) => quote!(#max),
+                None => quote!(::handshake::bitcoin::decode::MAX_SIZE),
+            };
+            quote! {
+                ::handshake::bitcoin::decode::decode_list(__bytes, #what, #max)?
+            }
+        } else if let Some(array) = element_wise(ty) {
+            let elem = &array.elem;
+            let len = &array.len;
+            quote! {{
+                let mut items = ::std::vec::Vec::with_capacity(#len);
+                for _ in 0..#len {
+                    items.push(<#elem as ::handshake::bitcoin::Decode>::decode(__bytes)?);
+                }
+                match <#ty as ::core::convert::TryFrom<_>>::try_from(items) {
+                    ::core::result::Result::Ok(array) => array,
+                    ::core::result::Result::Err(_) => unreachable!("exactly {} items were decoded", #len),
+                }
+            }}
+        } else {
+            quote! {
+                <#ty as ::handshake::bitcoin::Decode>::decode(__bytes)?
+            }
+        };
+        let local = &wire.local;
+        match &wire.attrs.since {
+            Some((version, min)) => quote! {
+                let #local: #ty = if #version >= #min {
+                    #decode
+                } else {
+                    ::core::default::Default::default()
+                };
+            },
+            None => quote! {
+                let #local: #ty = #decode;
+            },
+        }
+    });
+
+    let locals = fields.iter().map(|wire| &wire.local);
+    let construct = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(_) => quote!(Self { #(#locals),* }),
+            Fields::Unnamed(_) => quote!(Self(#(#locals),*)),
+            Fields::Unit => quote!(Self),
+        },
+        _ => unreachable!("wire_fields only accepts structs"),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::handshake::bitcoin::Decode for #name #ty_generics #where_clause {
+            fn decode(
+                __bytes: &mut #bytes::BytesMut,
+            ) -> ::handshake::bitcoin::Result<Self> {
+                #(#steps)*
+                ::core::result::Result::Ok(#construct)
+            }
+        }
+    })
+}
@@ -0,0 +1,12 @@
+//! Peer-to-peer handshakes and wire protocols, one module per network behind a Cargo feature of
+//! the same name: `bitcoin` (on by default) and `ethereum`.
+
+// Lets `#[derive(Encode, Decode)]` refer to `::handshake` from inside this crate too
+extern crate self as handshake;
+
+mod p2p;
+
+#[cfg(feature = "bitcoin")]
+pub use p2p::bitcoin;
+#[cfg(feature = "ethereum")]
+pub use p2p::ethereum;
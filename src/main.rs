@@ -1,18 +1,38 @@
-mod p2p;
-
-use p2p::bitcoin;
+use clap::Parser;
+use handshake::bitcoin;
+
+#[derive(Debug, Parser)]
+struct Args {
+    /// Peer to connect to, defaults to the first DNS seed of the network
+    endpoint: Option<String>,
+    /// mainnet, testnet3, testnet4, signet or regtest
+    #[arg(long, default_value_t = bitcoin::Network::Mainnet)]
+    network: bitcoin::Network,
+}
 
 /// For Bitcoin handshake
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
-
-    let endpoint = "seed.bitcoin.sipa.be:8333";
+    let args = Args::parse();
+
+    let network = args.network;
+    let endpoint = match args.endpoint {
+        Some(endpoint) => endpoint,
+        None => {
+            let seed = network
+                .dns_seeds()
+                .first()
+                .ok_or_else(|| anyhow::anyhow!("{network} has no DNS seeds, pass an endpoint"))?;
+            format!("{seed}:{}", network.default_port())
+        }
+    };
     tracing::info!("Connecting to {endpoint}");
-    let handshake = bitcoin::Handshake::connect(endpoint).await?;
+    let config = bitcoin::HandshakeConfig::new().network(network);
+    let handshake = bitcoin::Handshake::connect_with(endpoint, config).await?;
 
-    tracing::info!("Connected");
-    let (_tx, _rx) = handshake.split();
+    let (_tx, _rx, info) = handshake.split();
+    tracing::info!("Connected: {:?}", *info.borrow());
 
     Ok(())
 }
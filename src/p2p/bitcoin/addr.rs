@@ -0,0 +1,182 @@
+//! `addrv2` entries as specified in [BIP155](https://github.com/bitcoin/bips/blob/master/bip-0155.mediawiki).
+
+use super::{
+    encode::VariableInt, protocol::Port, services::ServiceFlags, Decode, Encode, Error, Result,
+};
+use bytes::{Buf, BufMut, BytesMut};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Longest address BIP155 allows for any network ID.
+pub const MAX_ADDRV2_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum NetworkAddress {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    /// Deprecated onion v2 address, still part of the encoding
+    TorV2([u8; 10]),
+    /// Ed25519 public key of an onion v3 service
+    TorV3([u8; 32]),
+    /// SHA256 of an I2P destination
+    I2p([u8; 32]),
+    Cjdns(Ipv6Addr),
+    /// Network ID this implementation doesn't know, kept so the entry round-trips
+    Unknown {
+        id: u8,
+        addr: Vec<u8>,
+    },
+}
+
+impl NetworkAddress {
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Ipv4(_) => 1,
+            Self::Ipv6(_) => 2,
+            Self::TorV2(_) => 3,
+            Self::TorV3(_) => 4,
+            Self::I2p(_) => 5,
+            Self::Cjdns(_) => 6,
+            Self::Unknown { id, .. } => *id,
+        }
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Ipv4(ip) => ip.octets().to_vec(),
+            Self::Ipv6(ip) | Self::Cjdns(ip) => ip.octets().to_vec(),
+            Self::TorV2(addr) => addr.to_vec(),
+            Self::TorV3(addr) | Self::I2p(addr) => addr.to_vec(),
+            Self::Unknown { addr, .. } => addr.clone(),
+        }
+    }
+
+    fn from_bytes(id: u8, addr: &[u8]) -> Result<Self> {
+        let invalid = || Error::InvalidAddress {
+            id,
+            length: addr.len(),
+        };
+        let address = match id {
+            1 => Self::Ipv4(<[u8; 4]>::try_from(addr).map_err(|_| invalid())?.into()),
+            2 => Self::Ipv6(<[u8; 16]>::try_from(addr).map_err(|_| invalid())?.into()),
+            3 => Self::TorV2(addr.try_into().map_err(|_| invalid())?),
+            4 => Self::TorV3(addr.try_into().map_err(|_| invalid())?),
+            5 => Self::I2p(addr.try_into().map_err(|_| invalid())?),
+            6 => {
+                let ip: [u8; 16] = addr.try_into().map_err(|_| invalid())?;
+                // CJDNS addresses always live in fc00::/8
+                if ip[0] != 0xfc {
+                    return Err(invalid());
+                }
+                Self::Cjdns(ip.into())
+            }
+            id => Self::Unknown {
+                id,
+                addr: addr.to_vec(),
+            },
+        };
+        Ok(address)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AddressV2 {
+    pub time: u32,
+    pub services: ServiceFlags,
+    pub addr: NetworkAddress,
+    pub port: Port,
+}
+
+impl Encode for AddressV2 {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        let addr = self.addr.bytes();
+        let mut written = self.time.encode(buffer);
+        written += VariableInt(self.services.bits()).encode(buffer);
+        written += self.addr.id().encode(buffer);
+        written += VariableInt(addr.len() as u64).encode(buffer);
+        buffer.put_slice(&addr);
+        written += addr.len();
+        written += self.port.encode(buffer);
+        written
+    }
+}
+
+impl Decode for AddressV2 {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        let time = u32::decode(bytes)?;
+        let services = VariableInt::decode(bytes)?.0.into();
+        let id = u8::decode(bytes)?;
+        let length = VariableInt::decode_length(bytes, "addrv2 address", MAX_ADDRV2_SIZE)?;
+        if bytes.remaining() < length {
+            return Err(Error::NotEnoughBytes("addrv2 address"));
+        }
+        let addr = NetworkAddress::from_bytes(id, &bytes[..length])?;
+        bytes.advance(length);
+        let port = Port::decode(bytes)?;
+        Ok(AddressV2 {
+            time,
+            services,
+            addr,
+            port,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn roundtrip_all_networks() {
+        let addresses = [
+            NetworkAddress::Ipv4("1.2.3.4".parse().unwrap()),
+            NetworkAddress::Ipv6("2a02:8308:900c:5900::1".parse().unwrap()),
+            NetworkAddress::TorV3([0x53; 32]),
+            NetworkAddress::I2p([0xa2; 32]),
+            NetworkAddress::Cjdns("fc00:1:2:3:4:5:6:7".parse().unwrap()),
+            NetworkAddress::Unknown {
+                id: 42,
+                addr: vec![1, 2, 3],
+            },
+        ];
+        for addr in addresses {
+            let entry = AddressV2 {
+                time: 1_700_000_000,
+                services: 1033.into(),
+                addr,
+                port: 8333.into(),
+            };
+            let mut buffer = BytesMut::new();
+            let written = entry.encode(&mut buffer);
+            assert_eq!(written, buffer.len());
+            assert_eq!(AddressV2::decode(&mut buffer).unwrap(), entry);
+            assert!(buffer.is_empty());
+        }
+    }
+
+    #[test]
+    fn decode_ipv4() {
+        // time, services (compact size 1), IPv4 id, 4 byte address, port 8333
+        let bin = b"\x00\x00\x00\x00\x01\x01\x04\x01\x02\x03\x04\x20\x8d";
+        let mut bytes = BytesMut::from(&bin[..]);
+        assert_eq!(
+            AddressV2::decode(&mut bytes).unwrap(),
+            AddressV2 {
+                time: 0,
+                services: 1.into(),
+                addr: NetworkAddress::Ipv4("1.2.3.4".parse().unwrap()),
+                port: 8333.into(),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        let bin = b"\x00\x00\x00\x00\x01\x01\x05\x01\x02\x03\x04\x05\x20\x8d";
+        let mut bytes = BytesMut::from(&bin[..]);
+        assert!(matches!(
+            AddressV2::decode(&mut bytes),
+            Err(Error::InvalidAddress { id: 1, length: 5 })
+        ));
+    }
+}
@@ -0,0 +1,231 @@
+use super::{
+    codec::MAX_PROTOCOL_MESSAGE_LENGTH,
+    decode::decode_list,
+    encode::{encode_list, VariableInt},
+    hashes::{merkle_root, Hash256},
+    transaction::Transaction,
+    Decode, Encode, Error, Result,
+};
+use bytes::BytesMut;
+
+/// Smallest possible serialized transaction (version, no inputs or outputs, lock time), used to
+/// bound the transaction count of a block.
+const MIN_TRANSACTION_SIZE: usize = 10;
+
+/// Bitcoin Core's `MAX_HEADERS_RESULTS`, the most headers a `headers` message may carry.
+pub const MAX_HEADERS_RESULTS: usize = 2000;
+
+/// Bitcoin Core's `MAX_LOCATOR_SZ`, the longest block locator a peer will accept.
+pub const MAX_LOCATOR_SZ: usize = 101;
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct BlockHeader {
+    pub version: i32,
+    pub prev_blockhash: Hash256,
+    pub merkle_root: Hash256,
+    pub time: u32,
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    pub const SIZE: usize = 80;
+
+    /// Block hash, the double SHA256 of the serialized header.
+    pub fn hash(&self) -> Hash256 {
+        let mut buffer = BytesMut::with_capacity(Self::SIZE);
+        self.encode(&mut buffer);
+        Hash256::hash(&buffer)
+    }
+}
+
+impl Encode for BlockHeader {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        let mut written = self.version.encode(buffer);
+        written += self.prev_blockhash.encode(buffer);
+        written += self.merkle_root.encode(buffer);
+        written += self.time.encode(buffer);
+        written += self.bits.encode(buffer);
+        written += self.nonce.encode(buffer);
+        written
+    }
+}
+
+impl Decode for BlockHeader {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        let version = i32::decode(bytes)?;
+        let prev_blockhash = Hash256::decode(bytes)?;
+        let merkle_root = Hash256::decode(bytes)?;
+        let time = u32::decode(bytes)?;
+        let bits = u32::decode(bytes)?;
+        let nonce = u32::decode(bytes)?;
+        Ok(BlockHeader {
+            version,
+            prev_blockhash,
+            merkle_root,
+            time,
+            bits,
+            nonce,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
+}
+
+impl Block {
+    pub fn hash(&self) -> Hash256 {
+        self.header.hash()
+    }
+
+    /// Merkle root over the txids, and whether the tree was mutated by duplicate transactions.
+    pub fn compute_merkle_root(&self) -> (Hash256, bool) {
+        merkle_root(self.transactions.iter().map(Transaction::txid).collect())
+    }
+
+    /// Checks the transactions against the merkle root committed to in the header.
+    pub fn check_merkle_root(&self) -> Result<()> {
+        let (actual, mutated) = self.compute_merkle_root();
+        if actual != self.header.merkle_root {
+            return Err(Error::MerkleRootMismatch {
+                expected: self.header.merkle_root,
+                actual,
+            });
+        }
+        if mutated {
+            return Err(Error::InvalidBlock("duplicate transactions in merkle tree"));
+        }
+        Ok(())
+    }
+}
+
+impl Encode for Block {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        self.header.encode(buffer) + encode_list(&self.transactions, buffer)
+    }
+}
+
+impl Decode for Block {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        let header = BlockHeader::decode(bytes)?;
+        let transactions = decode_list(
+            bytes,
+            "block transactions",
+            MAX_PROTOCOL_MESSAGE_LENGTH as usize / MIN_TRANSACTION_SIZE,
+        )?;
+        Ok(Block {
+            header,
+            transactions,
+        })
+    }
+}
+
+/// Entry of a `headers` message, a header followed by an always empty transaction count.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(super) struct HeadersEntry(pub(super) BlockHeader);
+
+impl Encode for HeadersEntry {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        self.0.encode(buffer) + VariableInt(0).encode(buffer)
+    }
+}
+
+impl Decode for HeadersEntry {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        let header = BlockHeader::decode(bytes)?;
+        if VariableInt::decode(bytes)?.0 != 0 {
+            return Err(Error::ProtocolViolation("headers entry with transactions"));
+        }
+        Ok(Self(header))
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Encode, Decode)]
+pub struct GetHeadersMessage {
+    pub version: u32,
+    /// Hashes we have, newest first, see [`locator_heights`]
+    #[wire(var_int, max = MAX_LOCATOR_SZ)]
+    pub locator: Vec<Hash256>,
+    /// Last header wanted, or zero for as many as the peer will send
+    pub stop_hash: Hash256,
+}
+
+/// Heights to put in a block locator for a chain whose tip is at `tip`.
+///
+/// The ten most recent blocks come one by one, then the step doubles until genesis, which is
+/// always the last entry.
+pub fn locator_heights(tip: u32) -> Vec<u32> {
+    let mut heights = Vec::new();
+    let mut height = tip;
+    let mut step = 1;
+    loop {
+        heights.push(height);
+        if height == 0 {
+            break;
+        }
+        if heights.len() >= 10 {
+            step *= 2;
+        }
+        height = height.saturating_sub(step);
+    }
+    heights
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::bitcoin::network::Network;
+
+    fn genesis() -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_blockhash: Hash256::ZERO,
+            merkle_root: "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"
+                .parse()
+                .unwrap(),
+            time: 1231006505,
+            bits: 0x1d00ffff,
+            nonce: 2083236893,
+        }
+    }
+
+    #[test]
+    fn genesis_hash() {
+        let header = genesis();
+        assert_eq!(header.hash(), Network::Mainnet.genesis_hash());
+
+        let mut buffer = BytesMut::new();
+        assert_eq!(header.encode(&mut buffer), BlockHeader::SIZE);
+        assert_eq!(BlockHeader::decode(&mut buffer).unwrap(), header);
+    }
+
+    #[test]
+    fn genesis_block() {
+        let block_hex = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+        let mut bytes = BytesMut::from(&hex::decode(block_hex).unwrap()[..]);
+        let mut block = Block::decode(&mut bytes).unwrap();
+        assert!(bytes.is_empty());
+        assert_eq!(block.header, genesis());
+        assert!(block.check_merkle_root().is_ok());
+
+        block.header.merkle_root = Hash256::ZERO;
+        assert!(matches!(
+            block.check_merkle_root(),
+            Err(Error::MerkleRootMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn locator() {
+        assert_eq!(locator_heights(0), [0]);
+        assert_eq!(locator_heights(5), [5, 4, 3, 2, 1, 0]);
+        assert_eq!(
+            locator_heights(100),
+            [100, 99, 98, 97, 96, 95, 94, 93, 92, 91, 89, 85, 77, 61, 29, 0]
+        );
+        assert!(locator_heights(u32::MAX).len() <= MAX_LOCATOR_SZ);
+    }
+}
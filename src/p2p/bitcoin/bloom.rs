@@ -0,0 +1,540 @@
+//! Connection bloom filtering from [BIP37](https://github.com/bitcoin/bips/blob/master/bip-0037.mediawiki).
+//!
+//! An SPV client loads a filter with `filterload`, the peer then only relays matching
+//! transactions and answers block requests with a `merkleblock` proving which of them the
+//! block contains.
+
+use super::{
+    block::{Block, BlockHeader},
+    decode::{decode_bytes, decode_list},
+    encode::{encode_bytes, encode_list},
+    hashes::Hash256,
+    transaction::OutPoint,
+    Decode, Encode, Error, Result,
+};
+use bytes::BytesMut;
+use std::f64::consts::LN_2;
+
+/// Bitcoin Core's `MAX_BLOOM_FILTER_SIZE`, in bytes.
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+
+/// Bitcoin Core's `MAX_HASH_FUNCS`.
+pub const MAX_HASH_FUNCS: u32 = 50;
+
+/// Bitcoin Core's `MAX_SCRIPT_ELEMENT_SIZE`, the largest element a `filteradd` may carry.
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+
+/// Bitcoin Core's bound on the transaction count a `merkleblock` may claim,
+/// `MAX_BLOCK_WEIGHT` over `MIN_TRANSACTION_WEIGHT`.
+const MAX_MERKLE_BLOCK_TRANSACTIONS: u32 = 16_666;
+
+/// How the peer updates the filter when a transaction output matches it.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum BloomFlags {
+    #[default]
+    None,
+    /// Add the outpoint of every matching output
+    All,
+    /// Add the outpoint only for pay-to-pubkey and bare multisig outputs
+    P2PubKeyOnly,
+    Unknown(u8),
+}
+
+impl From<u8> for BloomFlags {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::None,
+            1 => Self::All,
+            2 => Self::P2PubKeyOnly,
+            x => Self::Unknown(x),
+        }
+    }
+}
+
+impl From<BloomFlags> for u8 {
+    fn from(flags: BloomFlags) -> Self {
+        match flags {
+            BloomFlags::None => 0,
+            BloomFlags::All => 1,
+            BloomFlags::P2PubKeyOnly => 2,
+            BloomFlags::Unknown(x) => x,
+        }
+    }
+}
+
+/// Bloom filter, the payload of `filterload`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct BloomFilter {
+    pub data: Vec<u8>,
+    pub hash_funcs: u32,
+    /// Added to the seed of every hash function, so filters of different clients differ
+    pub tweak: u32,
+    pub flags: BloomFlags,
+}
+
+impl BloomFilter {
+    /// Empty filter sized for `elements` entries at a false positive rate of `fp_rate`, the
+    /// same way Bitcoin Core sizes it.
+    pub fn new(elements: u32, fp_rate: f64, tweak: u32, flags: BloomFlags) -> Self {
+        let elements = elements.max(1);
+        let bits = (-1.0 / (LN_2 * LN_2) * f64::from(elements) * fp_rate.ln()) as usize;
+        let size = bits.min(MAX_BLOOM_FILTER_SIZE * 8) / 8;
+        let hash_funcs = ((size * 8) as u32 / elements) as f64 * LN_2;
+        BloomFilter {
+            data: vec![0; size],
+            hash_funcs: (hash_funcs as u32).min(MAX_HASH_FUNCS),
+            tweak,
+            flags,
+        }
+    }
+
+    pub fn insert(&mut self, element: &[u8]) {
+        if self.data.is_empty() {
+            return;
+        }
+        for n in 0..self.hash_funcs {
+            let bit = self.bit(n, element);
+            self.data[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Inserts the serialized outpoint, what the peer matches spending inputs against.
+    pub fn insert_outpoint(&mut self, outpoint: &OutPoint) {
+        let mut buffer = BytesMut::with_capacity(36);
+        outpoint.encode(&mut buffer);
+        self.insert(&buffer);
+    }
+
+    pub fn contains(&self, element: &[u8]) -> bool {
+        if self.data.is_empty() {
+            return false;
+        }
+        (0..self.hash_funcs).all(|n| {
+            let bit = self.bit(n, element);
+            self.data[bit / 8] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    fn bit(&self, n: u32, element: &[u8]) -> usize {
+        let seed = n.wrapping_mul(0xFBA4_C795).wrapping_add(self.tweak);
+        murmur3(element, seed) as usize % (self.data.len() * 8)
+    }
+}
+
+impl Encode for BloomFilter {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        let mut written = encode_bytes(&self.data, buffer);
+        written += self.hash_funcs.encode(buffer);
+        written += self.tweak.encode(buffer);
+        written += u8::from(self.flags).encode(buffer);
+        written
+    }
+}
+
+impl Decode for BloomFilter {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        let data = decode_bytes(bytes, "bloom filter", MAX_BLOOM_FILTER_SIZE)?;
+        let hash_funcs = u32::decode(bytes)?;
+        if hash_funcs > MAX_HASH_FUNCS {
+            return Err(Error::ProtocolViolation(
+                "too many bloom filter hash functions",
+            ));
+        }
+        let tweak = u32::decode(bytes)?;
+        let flags = u8::decode(bytes)?.into();
+        Ok(BloomFilter {
+            data,
+            hash_funcs,
+            tweak,
+            flags,
+        })
+    }
+}
+
+/// Payload of `filteradd`, a single element added to the loaded filter.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FilterAdd(pub Vec<u8>);
+
+impl Encode for FilterAdd {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        encode_bytes(&self.0, buffer)
+    }
+}
+
+impl Decode for FilterAdd {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        Ok(FilterAdd(decode_bytes(
+            bytes,
+            "filteradd",
+            MAX_SCRIPT_ELEMENT_SIZE,
+        )?))
+    }
+}
+
+/// 32-bit MurmurHash3, the hash function of BIP37 filters.
+fn murmur3(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let mut h = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        let mut k = u32::from_le_bytes(block.try_into().expect("4 bytes"));
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let mut k = tail
+            .iter()
+            .rev()
+            .fold(0_u32, |k, byte| k << 8 | u32::from(*byte));
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    h
+}
+
+/// Payload of `merkleblock`, a header and a partial merkle tree proving which transactions
+/// matched the filter.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MerkleBlock {
+    pub header: BlockHeader,
+    pub total_transactions: u32,
+    /// Hashes of the tree, depth first
+    pub hashes: Vec<Hash256>,
+    /// One bit per visited node, whether it is or contains a match, least significant first
+    pub flags: Vec<u8>,
+}
+
+impl MerkleBlock {
+    /// Partial merkle tree of `block` over the transactions `matches` picks.
+    pub fn from_block(block: &Block, matches: impl Fn(&Hash256) -> bool) -> Self {
+        let txids: Vec<Hash256> = block.transactions.iter().map(|tx| tx.txid()).collect();
+        let matched: Vec<bool> = txids.iter().map(matches).collect();
+        let mut tree = PartialTree {
+            total: txids.len() as u32,
+            bits: Vec::new(),
+            hashes: Vec::new(),
+        };
+        tree.build(tree.height(), 0, &txids, &matched);
+
+        let mut flags = vec![0; tree.bits.len().div_ceil(8)];
+        for (i, bit) in tree.bits.iter().enumerate() {
+            flags[i / 8] |= u8::from(*bit) << (i % 8);
+        }
+        MerkleBlock {
+            header: block.header,
+            total_transactions: tree.total,
+            hashes: tree.hashes,
+            flags,
+        }
+    }
+
+    /// Walks the partial merkle tree, checks it against the header's merkle root and returns
+    /// the matched txids in block order.
+    pub fn extract_matches(&self) -> Result<Vec<Hash256>> {
+        if self.total_transactions == 0 {
+            return Err(Error::InvalidMerkleBlock("no transactions"));
+        }
+        if self.total_transactions > MAX_MERKLE_BLOCK_TRANSACTIONS {
+            return Err(Error::InvalidMerkleBlock("too many transactions"));
+        }
+        if self.hashes.len() > self.total_transactions as usize {
+            return Err(Error::InvalidMerkleBlock("more hashes than transactions"));
+        }
+        if self.flags.len() * 8 < self.hashes.len() {
+            return Err(Error::InvalidMerkleBlock("fewer flag bits than hashes"));
+        }
+
+        let tree = PartialTree {
+            total: self.total_transactions,
+            bits: Vec::new(),
+            hashes: Vec::new(),
+        };
+        let mut walk = Walk {
+            block: self,
+            bits_used: 0,
+            hashes_used: 0,
+            matches: Vec::new(),
+        };
+        let root = walk.traverse(&tree, tree.height(), 0)?;
+        if walk.bits_used.div_ceil(8) != self.flags.len() {
+            return Err(Error::InvalidMerkleBlock("unused flag bits"));
+        }
+        if walk.hashes_used != self.hashes.len() {
+            return Err(Error::InvalidMerkleBlock("unused hashes"));
+        }
+        if root != self.header.merkle_root {
+            return Err(Error::MerkleRootMismatch {
+                expected: self.header.merkle_root,
+                actual: root,
+            });
+        }
+        Ok(walk.matches)
+    }
+}
+
+impl Encode for MerkleBlock {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        let mut written = self.header.encode(buffer);
+        written += self.total_transactions.encode(buffer);
+        written += encode_list(&self.hashes, buffer);
+        written += encode_bytes(&self.flags, buffer);
+        written
+    }
+}
+
+impl Decode for MerkleBlock {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        let header = BlockHeader::decode(bytes)?;
+        let total_transactions = u32::decode(bytes)?;
+        let limit = MAX_MERKLE_BLOCK_TRANSACTIONS as usize;
+        let hashes = decode_list(bytes, "merkleblock hashes", limit)?;
+        let flags = decode_bytes(bytes, "merkleblock flags", (2 * limit).div_ceil(8))?;
+        Ok(MerkleBlock {
+            header,
+            total_transactions,
+            hashes,
+            flags,
+        })
+    }
+}
+
+fn parent(left: &Hash256, right: &Hash256) -> Hash256 {
+    Hash256::hash(&[left.0, right.0].concat())
+}
+
+/// Shape of a merkle tree over `total` leaves, plus the bits and hashes of a partial one
+/// while it is being built.
+struct PartialTree {
+    total: u32,
+    bits: Vec<bool>,
+    hashes: Vec<Hash256>,
+}
+
+impl PartialTree {
+    fn width(&self, height: u32) -> u32 {
+        (self.total + (1 << height) - 1) >> height
+    }
+
+    fn height(&self) -> u32 {
+        let mut height = 0;
+        while self.width(height) > 1 {
+            height += 1;
+        }
+        height
+    }
+
+    fn hash(&self, height: u32, pos: u32, txids: &[Hash256]) -> Hash256 {
+        if height == 0 {
+            return txids[pos as usize];
+        }
+        let left = self.hash(height - 1, pos * 2, txids);
+        let right = if pos * 2 + 1 < self.width(height - 1) {
+            self.hash(height - 1, pos * 2 + 1, txids)
+        } else {
+            left
+        };
+        parent(&left, &right)
+    }
+
+    fn build(&mut self, height: u32, pos: u32, txids: &[Hash256], matched: &[bool]) {
+        let start = (pos << height) as usize;
+        let end = (((pos + 1) << height) as usize).min(matched.len());
+        let parent_of_match = matched[start..end].iter().any(|m| *m);
+        self.bits.push(parent_of_match);
+        if height == 0 || !parent_of_match {
+            let hash = self.hash(height, pos, txids);
+            self.hashes.push(hash);
+            return;
+        }
+        self.build(height - 1, pos * 2, txids, matched);
+        if pos * 2 + 1 < self.width(height - 1) {
+            self.build(height - 1, pos * 2 + 1, txids, matched);
+        }
+    }
+}
+
+/// State of [`MerkleBlock::extract_matches`] while walking the tree.
+struct Walk<'a> {
+    block: &'a MerkleBlock,
+    bits_used: usize,
+    hashes_used: usize,
+    matches: Vec<Hash256>,
+}
+
+impl Walk<'_> {
+    fn traverse(&mut self, tree: &PartialTree, height: u32, pos: u32) -> Result<Hash256> {
+        let byte = self
+            .block
+            .flags
+            .get(self.bits_used / 8)
+            .ok_or(Error::InvalidMerkleBlock("ran out of flag bits"))?;
+        let parent_of_match = byte & (1 << (self.bits_used % 8)) != 0;
+        self.bits_used += 1;
+
+        if height == 0 || !parent_of_match {
+            let hash = *self
+                .block
+                .hashes
+                .get(self.hashes_used)
+                .ok_or(Error::InvalidMerkleBlock("ran out of hashes"))?;
+            self.hashes_used += 1;
+            if height == 0 && parent_of_match {
+                self.matches.push(hash);
+            }
+            return Ok(hash);
+        }
+
+        let left = self.traverse(tree, height - 1, pos * 2)?;
+        let right = if pos * 2 + 1 < tree.width(height - 1) {
+            let right = self.traverse(tree, height - 1, pos * 2 + 1)?;
+            // CVE-2012-2459, identical siblings let a tree pass off a different transaction set
+            if right == left {
+                return Err(Error::InvalidMerkleBlock("duplicate sibling hashes"));
+            }
+            right
+        } else {
+            left
+        };
+        Ok(parent(&left, &right))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::bitcoin::transaction::{Transaction, TxIn};
+
+    #[test]
+    fn bloom_filter_core_vectors() {
+        let elements = [
+            "99108ad8ed9bb6274d3980bab5a85c048f0950c8",
+            "b5a2c786d9ef4658287ced5914b37a1b4aa32eee",
+            "b9300670b4c5366e95b2699e8b18bc75e5f729c5",
+        ];
+        for (tweak, expected) in [
+            (0, "03614e9b050000000000000001"),
+            (2147483649, "03ce4299050000000100008001"),
+        ] {
+            let mut filter = BloomFilter::new(3, 0.01, tweak, BloomFlags::All);
+            filter.insert(&hex::decode(elements[0]).unwrap());
+            assert!(filter.contains(&hex::decode(elements[0]).unwrap()));
+            assert!(
+                !filter.contains(&hex::decode("19108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap())
+            );
+            for element in &elements[1..] {
+                filter.insert(&hex::decode(element).unwrap());
+            }
+
+            let mut buffer = BytesMut::new();
+            filter.encode(&mut buffer);
+            assert_eq!(hex::encode(&buffer), expected);
+            assert_eq!(BloomFilter::decode(&mut buffer).unwrap(), filter);
+        }
+    }
+
+    #[test]
+    fn murmur3_vectors() {
+        assert_eq!(murmur3(&[], 0), 0);
+        assert_eq!(murmur3(&[], 0xFBA4C795), 0x6a396f08);
+        assert_eq!(murmur3(&[0x00], 0), 0x514E28B7);
+        assert_eq!(murmur3(&[0x21, 0x43, 0x65, 0x87], 0), 0xF55B516B);
+        assert_eq!(murmur3(&[0x21, 0x43, 0x65], 0), 0x7E4A8634);
+    }
+
+    fn block(count: usize) -> Block {
+        let transactions = (0..count)
+            .map(|i| Transaction {
+                version: i as i32,
+                inputs: vec![TxIn::default()],
+                ..Default::default()
+            })
+            .collect();
+        let mut block = Block {
+            transactions,
+            ..Default::default()
+        };
+        block.header.merkle_root = block.compute_merkle_root().0;
+        block
+    }
+
+    #[test]
+    fn partial_merkle_tree_roundtrip() {
+        for count in [1, 2, 3, 7, 17, 56] {
+            let block = block(count);
+            let txids: Vec<Hash256> = block.transactions.iter().map(|tx| tx.txid()).collect();
+            for stride in [1, 2, 5, count + 1] {
+                let wanted: Vec<Hash256> = txids.iter().step_by(stride).copied().collect();
+                let merkle_block = MerkleBlock::from_block(&block, |txid| wanted.contains(txid));
+
+                let mut buffer = BytesMut::new();
+                merkle_block.encode(&mut buffer);
+                let decoded = MerkleBlock::decode(&mut buffer).unwrap();
+                assert_eq!(decoded, merkle_block);
+                assert_eq!(decoded.extract_matches().unwrap(), wanted);
+            }
+        }
+    }
+
+    #[test]
+    fn tampered_merkle_blocks() {
+        let block = block(5);
+        let txid = block.transactions[3].txid();
+        let merkle_block = MerkleBlock::from_block(&block, |hash| *hash == txid);
+        assert_eq!(merkle_block.extract_matches().unwrap(), [txid]);
+
+        let mut wrong_hash = merkle_block.clone();
+        wrong_hash.hashes[0] = Hash256::ZERO;
+        assert!(matches!(
+            wrong_hash.extract_matches(),
+            Err(Error::MerkleRootMismatch { .. })
+        ));
+
+        let mut extra_hash = merkle_block.clone();
+        extra_hash.hashes.push(Hash256::ZERO);
+        assert!(matches!(
+            extra_hash.extract_matches(),
+            Err(Error::InvalidMerkleBlock("unused hashes"))
+        ));
+
+        let mut extra_flags = merkle_block.clone();
+        extra_flags.flags.push(0);
+        assert!(matches!(
+            extra_flags.extract_matches(),
+            Err(Error::InvalidMerkleBlock("unused flag bits"))
+        ));
+
+        let mut wrong_count = merkle_block.clone();
+        wrong_count.total_transactions = 4;
+        assert!(wrong_count.extract_matches().is_err());
+    }
+
+    #[test]
+    fn duplicate_siblings_rejected() {
+        // Three transactions hash like four with the last one repeated, the walk must refuse
+        // the explicit duplicate
+        let block = block(3);
+        let mut txids: Vec<Hash256> = block.transactions.iter().map(|tx| tx.txid()).collect();
+        txids.push(txids[2]);
+        let merkle_block = MerkleBlock {
+            header: block.header,
+            total_transactions: 4,
+            hashes: txids,
+            flags: vec![0b0111_1111],
+        };
+        assert!(matches!(
+            merkle_block.extract_matches(),
+            Err(Error::InvalidMerkleBlock("duplicate sibling hashes"))
+        ));
+    }
+}
@@ -0,0 +1,445 @@
+//! In-memory header tree with proof of work and contextual validation.
+
+use super::{
+    block::{locator_heights, BlockHeader},
+    hashes::Hash256,
+    network::Network,
+    pow::U256,
+    Error, Result,
+};
+use std::collections::{BTreeMap, HashMap};
+
+/// Headers may be at most this far ahead of our clock, in seconds.
+pub const MAX_FUTURE_BLOCK_TIME: u32 = 2 * 60 * 60;
+
+const MEDIAN_TIME_SPAN: usize = 11;
+const TARGET_TIMESPAN: u32 = 14 * 24 * 60 * 60;
+const TARGET_SPACING: u32 = 10 * 60;
+const DIFFICULTY_ADJUSTMENT_INTERVAL: u32 = TARGET_TIMESPAN / TARGET_SPACING;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ChainEntry {
+    pub header: BlockHeader,
+    pub hash: Hash256,
+    pub height: u32,
+    /// Total work of the chain up to and including this header
+    pub chain_work: U256,
+}
+
+/// Outcome of connecting a single valid header.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Connected {
+    /// We already had this header
+    Duplicate,
+    /// The header became the new tip of the best chain
+    Extended { height: u32 },
+    /// Stored on a side chain with less work than the best one
+    SideChain { height: u32 },
+    /// A side chain overtook the best chain
+    Reorg {
+        fork_height: u32,
+        disconnected: Vec<Hash256>,
+        connected: Vec<Hash256>,
+    },
+}
+
+/// Every valid header we've seen, plus the chain with the most work.
+#[derive(Debug, Clone)]
+pub struct HeaderChain {
+    network: Network,
+    entries: HashMap<Hash256, ChainEntry>,
+    active: Vec<Hash256>,
+    checkpoints: BTreeMap<u32, Hash256>,
+}
+
+impl HeaderChain {
+    pub fn new(network: Network) -> Self {
+        let header = network.genesis_header();
+        let hash = header.hash();
+        let genesis = ChainEntry {
+            header,
+            hash,
+            height: 0,
+            chain_work: U256::from_compact(header.bits).0.work(),
+        };
+        Self {
+            network,
+            entries: HashMap::from([(hash, genesis)]),
+            active: vec![hash],
+            checkpoints: network.checkpoints().into_iter().collect(),
+        }
+    }
+
+    /// Replaces the network's built-in checkpoints.
+    pub fn with_checkpoints(
+        mut self,
+        checkpoints: impl IntoIterator<Item = (u32, Hash256)>,
+    ) -> Self {
+        self.checkpoints = checkpoints.into_iter().collect();
+        self
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn tip(&self) -> &ChainEntry {
+        let hash = self.active.last().expect("the chain always holds genesis");
+        &self.entries[hash]
+    }
+
+    pub fn height(&self) -> u32 {
+        self.tip().height
+    }
+
+    pub fn get(&self, hash: &Hash256) -> Option<&ChainEntry> {
+        self.entries.get(hash)
+    }
+
+    /// Header of the best chain at `height`.
+    pub fn at_height(&self, height: u32) -> Option<&ChainEntry> {
+        self.active
+            .get(height as usize)
+            .map(|hash| &self.entries[hash])
+    }
+
+    pub fn is_active(&self, entry: &ChainEntry) -> bool {
+        self.active.get(entry.height as usize) == Some(&entry.hash)
+    }
+
+    /// Block locator for the best chain, to be sent in `getheaders`.
+    pub fn locator(&self) -> Vec<Hash256> {
+        locator_heights(self.height())
+            .into_iter()
+            .map(|height| self.active[height as usize])
+            .collect()
+    }
+
+    /// Validates `header` against its parent and adds it to the tree.
+    ///
+    /// `now` is the current UNIX time, headers too far in the future are rejected.
+    pub fn connect(&mut self, header: BlockHeader, now: u32) -> Result<Connected> {
+        let hash = header.hash();
+        if self.entries.contains_key(&hash) {
+            return Ok(Connected::Duplicate);
+        }
+        let prev = *self
+            .entries
+            .get(&header.prev_blockhash)
+            .ok_or(Error::UnconnectedHeader {
+                hash,
+                prev: header.prev_blockhash,
+            })?;
+        let height = prev.height + 1;
+        let invalid = |reason| Error::InvalidHeader { hash, reason };
+
+        let (target, negative, overflow) = U256::from_compact(header.bits);
+        if negative || overflow || target.is_zero() || target > self.network.pow_limit() {
+            return Err(invalid("target out of range"));
+        }
+        if U256::from(hash) > target {
+            return Err(invalid("proof of work does not match bits"));
+        }
+        if header.bits != self.next_work_required(&prev, &header) {
+            return Err(invalid("incorrect difficulty"));
+        }
+        if header.time <= self.median_time_past(&prev) {
+            return Err(invalid("time is not after the median time past"));
+        }
+        if header.time > now.saturating_add(MAX_FUTURE_BLOCK_TIME) {
+            return Err(invalid("time too far in the future"));
+        }
+        // BIP94 timewarp fix, the first block of a period can't go back more than 10 minutes
+        if self.network.enforce_bip94()
+            && height.is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL)
+            && header.time < prev.header.time.saturating_sub(TARGET_SPACING)
+        {
+            return Err(invalid("timewarp attack"));
+        }
+        if let Some(checkpoint) = self.checkpoints.get(&height) {
+            if *checkpoint != hash {
+                return Err(invalid("conflicts with a checkpoint"));
+            }
+        }
+        let last_checkpoint = self
+            .checkpoints
+            .range(..=self.height())
+            .next_back()
+            .map(|(height, _)| *height);
+        if last_checkpoint.is_some_and(|checkpoint| height <= checkpoint) {
+            return Err(invalid("forks before the last checkpoint"));
+        }
+
+        let entry = ChainEntry {
+            header,
+            hash,
+            height,
+            chain_work: prev.chain_work + target.work(),
+        };
+        self.entries.insert(hash, entry);
+
+        let tip = self.tip();
+        if entry.chain_work <= tip.chain_work {
+            return Ok(Connected::SideChain { height });
+        }
+        if prev.hash == tip.hash {
+            self.active.push(hash);
+            return Ok(Connected::Extended { height });
+        }
+
+        let mut connected = vec![hash];
+        let mut fork = prev;
+        while !self.is_active(&fork) {
+            connected.push(fork.hash);
+            fork = self.entries[&fork.header.prev_blockhash];
+        }
+        connected.reverse();
+        let disconnected = self.active.split_off(fork.height as usize + 1);
+        self.active.extend_from_slice(&connected);
+        Ok(Connected::Reorg {
+            fork_height: fork.height,
+            disconnected,
+            connected,
+        })
+    }
+
+    /// Ancestor of `entry` at `height`, which must not be above the entry.
+    fn ancestor(&self, entry: &ChainEntry, height: u32) -> ChainEntry {
+        let mut current = *entry;
+        while current.height > height {
+            if self.is_active(&current) {
+                return *self.at_height(height).expect("active chain is contiguous");
+            }
+            current = self.entries[&current.header.prev_blockhash];
+        }
+        current
+    }
+
+    fn median_time_past(&self, entry: &ChainEntry) -> u32 {
+        let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut current = Some(entry);
+        while let Some(entry) = current.filter(|_| times.len() < MEDIAN_TIME_SPAN) {
+            times.push(entry.header.time);
+            current = self.entries.get(&entry.header.prev_blockhash);
+        }
+        times.sort_unstable();
+        times[times.len() / 2]
+    }
+
+    /// Bitcoin Core's `GetNextWorkRequired`, the `bits` a child of `prev` must have.
+    fn next_work_required(&self, prev: &ChainEntry, header: &BlockHeader) -> u32 {
+        let pow_limit = self.network.pow_limit().to_compact();
+        let height = prev.height + 1;
+
+        if !height.is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL) {
+            if !self.network.allow_min_difficulty_blocks() {
+                return prev.header.bits;
+            }
+            // Testnets allow a minimum difficulty block after 20 minutes without one
+            if header.time > prev.header.time + TARGET_SPACING * 2 {
+                return pow_limit;
+            }
+            // Otherwise the last block that wasn't mined under that exception sets the pace
+            let mut current = *prev;
+            while !current
+                .height
+                .is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL)
+                && current.header.bits == pow_limit
+            {
+                current = self.entries[&current.header.prev_blockhash];
+            }
+            return current.header.bits;
+        }
+
+        if self.network.no_retargeting() {
+            return prev.header.bits;
+        }
+        let first = self.ancestor(prev, height - DIFFICULTY_ADJUSTMENT_INTERVAL);
+        let base_bits = if self.network.enforce_bip94() {
+            first.header.bits
+        } else {
+            prev.header.bits
+        };
+        retarget(
+            base_bits,
+            first.header.time,
+            prev.header.time,
+            self.network.pow_limit(),
+        )
+    }
+}
+
+/// Bitcoin Core's `CalculateNextWorkRequired`: scales the target by how long the last period
+/// actually took, at most by a factor of four either way.
+fn retarget(bits: u32, first_time: u32, last_time: u32, pow_limit: U256) -> u32 {
+    let actual = (last_time as i64 - first_time as i64)
+        .clamp(TARGET_TIMESPAN as i64 / 4, TARGET_TIMESPAN as i64 * 4);
+    let (target, _, _) = U256::from_compact(bits);
+    let target = target
+        .checked_mul_u64(actual as u64)
+        .map(|scaled| scaled.div_u64(TARGET_TIMESPAN as u64))
+        .unwrap_or(pow_limit);
+    target.min(pow_limit).to_compact()
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// Grinds nonces until `header` meets its own target, cheap on regtest.
+    pub(in crate::p2p::bitcoin) fn mine(mut header: BlockHeader) -> BlockHeader {
+        let (target, _, _) = U256::from_compact(header.bits);
+        while U256::from(header.hash()) > target {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    /// `count` regtest headers on top of `parent`, ten minutes apart.
+    pub(in crate::p2p::bitcoin) fn extend(
+        parent: &BlockHeader,
+        count: usize,
+        salt: u8,
+    ) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = Vec::with_capacity(count);
+        for _ in 0..count {
+            let prev = headers.last().unwrap_or(parent);
+            headers.push(mine(BlockHeader {
+                version: 4,
+                prev_blockhash: prev.hash(),
+                merkle_root: Hash256([salt; 32]),
+                time: prev.time + TARGET_SPACING,
+                bits: prev.bits,
+                nonce: 0,
+            }));
+        }
+        headers
+    }
+
+    const NOW: u32 = u32::MAX - MAX_FUTURE_BLOCK_TIME;
+
+    #[test]
+    fn extend_and_reorg() {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let genesis = Network::Regtest.genesis_header();
+        let main = extend(&genesis, 5, 1);
+        for (i, header) in main.iter().enumerate() {
+            assert_eq!(
+                chain.connect(*header, NOW).unwrap(),
+                Connected::Extended {
+                    height: i as u32 + 1
+                }
+            );
+        }
+        assert_eq!(chain.connect(main[4], NOW).unwrap(), Connected::Duplicate);
+
+        let fork = extend(&main[1], 4, 2);
+        for (i, header) in fork[..3].iter().enumerate() {
+            assert_eq!(
+                chain.connect(*header, NOW).unwrap(),
+                Connected::SideChain {
+                    height: i as u32 + 3
+                }
+            );
+        }
+        assert_eq!(
+            chain.connect(fork[3], NOW).unwrap(),
+            Connected::Reorg {
+                fork_height: 2,
+                disconnected: main[2..].iter().map(BlockHeader::hash).collect(),
+                connected: fork.iter().map(BlockHeader::hash).collect(),
+            }
+        );
+        assert_eq!(chain.height(), 6);
+        assert_eq!(chain.tip().hash, fork[3].hash());
+        assert_eq!(chain.locator().last(), Some(&genesis.hash()));
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        let mut chain = HeaderChain::new(Network::Regtest);
+        let genesis = Network::Regtest.genesis_header();
+
+        let orphan = extend(&extend(&genesis, 1, 1)[0], 1, 1)[0];
+        assert!(matches!(
+            chain.connect(orphan, NOW),
+            Err(Error::UnconnectedHeader { .. })
+        ));
+
+        let mut bad_bits = extend(&genesis, 1, 1)[0];
+        bad_bits.bits = 0x1d00ffff;
+        assert!(matches!(
+            chain.connect(bad_bits, NOW),
+            Err(Error::InvalidHeader { .. })
+        ));
+
+        let mut no_work = extend(&genesis, 1, 1)[0];
+        while U256::from(no_work.hash()) <= U256::from_compact(no_work.bits).0 {
+            no_work.nonce += 1;
+        }
+        assert!(matches!(
+            chain.connect(no_work, NOW),
+            Err(Error::InvalidHeader { reason, .. }) if reason.contains("proof of work")
+        ));
+
+        let mut old = genesis;
+        old.prev_blockhash = genesis.hash();
+        let old = mine(old);
+        assert!(matches!(
+            chain.connect(old, NOW),
+            Err(Error::InvalidHeader { reason, .. }) if reason.contains("median time past")
+        ));
+
+        let mut future = extend(&genesis, 1, 1)[0];
+        future.time = genesis.time + MAX_FUTURE_BLOCK_TIME + 1;
+        let future = mine(future);
+        assert!(matches!(
+            chain.connect(future, genesis.time),
+            Err(Error::InvalidHeader { reason, .. }) if reason.contains("future")
+        ));
+    }
+
+    #[test]
+    fn checkpoints() {
+        let genesis = Network::Regtest.genesis_header();
+        let main = extend(&genesis, 3, 1);
+        let fork = extend(&genesis, 3, 2);
+        let mut chain = HeaderChain::new(Network::Regtest).with_checkpoints([(2, main[1].hash())]);
+        assert!(chain.connect(fork[0], NOW).is_ok());
+        assert!(matches!(
+            chain.connect(fork[1], NOW),
+            Err(Error::InvalidHeader { reason, .. }) if reason.contains("checkpoint")
+        ));
+        for header in &main {
+            chain.connect(*header, NOW).unwrap();
+        }
+        // Below the last checkpoint nothing new may fork off anymore
+        let late_fork = extend(&genesis, 1, 3)[0];
+        assert!(matches!(
+            chain.connect(late_fork, NOW),
+            Err(Error::InvalidHeader { reason, .. }) if reason.contains("last checkpoint")
+        ));
+    }
+
+    #[test]
+    fn retarget_vectors() {
+        // From Bitcoin Core's pow_tests.cpp
+        let pow_limit = Network::Mainnet.pow_limit();
+        assert_eq!(
+            retarget(0x1d00ffff, 1261130161, 1262152739, pow_limit),
+            0x1d00d86a
+        );
+        assert_eq!(
+            retarget(0x1d00ffff, 1231006505, 1233061996, pow_limit),
+            0x1d00ffff
+        );
+        assert_eq!(
+            retarget(0x1c05a3f4, 1279008237, 1279297671, pow_limit),
+            0x1c0168fd
+        );
+        assert_eq!(
+            retarget(0x1c387f6f, 1263163443, 1269211443, pow_limit),
+            0x1d00e1fd
+        );
+    }
+}
@@ -1,15 +1,113 @@
 // pub use super::{Decode, Encode, Error, Message, Result};
-use super::{decode::Decode, encode::Encode, error::Error, protocol::Message};
+use super::{
+    encode::Encode,
+    error::Error,
+    network::Network,
+    protocol::{Command, Message},
+    transport::V2Session,
+};
 use bytes::BytesMut;
+use std::collections::HashMap;
 use tokio_util::codec::{Decoder, Encoder};
 
-pub struct BitcoinCodec;
+/// Bitcoin Core's `MAX_PROTOCOL_MESSAGE_LENGTH`, the largest payload we accept by default.
+pub const MAX_PROTOCOL_MESSAGE_LENGTH: u32 = 4_000_000;
+
+const HEADER_LENGTH: usize = 24;
+
+/// Payload size caps enforced by [`BitcoinCodec`] before a frame is buffered.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Limits {
+    max_payload: u32,
+    per_command: HashMap<Command, u32>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_payload: MAX_PROTOCOL_MESSAGE_LENGTH,
+            per_command: HashMap::new(),
+        }
+    }
+}
+
+impl Limits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cap applied to every command without a more specific limit.
+    pub fn max_payload(mut self, length: u32) -> Self {
+        self.max_payload = length;
+        self
+    }
+
+    /// Cap for a single command, it may be larger than the global one.
+    pub fn command(mut self, command: Command, length: u32) -> Self {
+        self.per_command.insert(command, length);
+        self
+    }
+
+    pub fn limit(&self, command: &Command) -> u32 {
+        self.per_command
+            .get(command)
+            .copied()
+            .unwrap_or(self.max_payload)
+    }
+
+    /// Highest limit of any command, all a v2 length field can be checked against.
+    pub(super) fn largest(&self) -> u32 {
+        self.per_command
+            .values()
+            .copied()
+            .fold(self.max_payload, u32::max)
+    }
+}
+
+/// Frames messages with the plaintext v1 header, or as BIP324 packets once a v2 session is
+/// set.
+#[derive(Debug, Clone, Default)]
+pub struct BitcoinCodec {
+    network: Network,
+    limits: Limits,
+    v2: Option<V2Session>,
+}
+
+impl BitcoinCodec {
+    pub fn new(network: Network) -> Self {
+        Self {
+            network,
+            limits: Limits::default(),
+            v2: None,
+        }
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Switches to the v2 transport, for a session that completed its key exchange.
+    pub fn with_v2(mut self, session: V2Session) -> Self {
+        self.v2 = Some(session);
+        self
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+}
 
 impl Encoder<Message> for BitcoinCodec {
     type Error = Error;
 
     fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        message.encode(dst);
+        match &mut self.v2 {
+            Some(session) => session.encode(&message, dst),
+            None => {
+                message.encode(dst);
+            }
+        }
         Ok(())
     }
 }
@@ -19,22 +117,152 @@ impl Decoder for BitcoinCodec {
     type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        tracing::info!("Decoding message {src:?}");
-        if src.is_empty() || src.len() < 24 {
+        // Called again for every chunk of a large frame, so never format the whole buffer here
+        tracing::trace!("Decoding message, {} bytes buffered", src.len());
+        if let Some(session) = &mut self.v2 {
+            return session.decode(src, self.network, &self.limits);
+        }
+        if src.is_empty() || src.len() < HEADER_LENGTH {
             // Not enough bytes
             return Ok(None);
         }
 
+        // Fail on the wrong magic before waiting for a payload of a length that means nothing
+        let magic = u32::from_le_bytes([src[0], src[1], src[2], src[3]]);
+        if magic != self.network.magic() {
+            return Err(Error::BadMagic {
+                expected: self.network.magic(),
+                actual: magic,
+            });
+        }
+
         // Length of payload starts at 16th byte, and is 4 bytes long
         let payload_length = u32::from_le_bytes([src[16], src[17], src[18], src[19]]);
 
-        if src.len() < 24 + payload_length as usize {
+        let command = Command::from(<[u8; 12]>::try_from(&src[4..16])?);
+        let limit = self.limits.limit(&command);
+        if payload_length > limit {
+            return Err(Error::MessageTooLarge {
+                command: command.to_string(),
+                length: payload_length,
+                limit,
+            });
+        }
+
+        let frame_length = HEADER_LENGTH + payload_length as usize;
+        if src.len() < frame_length {
             // Not enough bytes
+            src.reserve(frame_length - src.len());
             return Ok(None);
         }
 
-        let message = Message::decode(src)?;
+        let message = Message::decode(src, self.network)?;
 
         Ok(Some(message))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::bitcoin::protocol::{Command, Payload};
+
+    #[test]
+    fn rejects_foreign_magic() {
+        let mut bytes = BytesMut::new();
+        BitcoinCodec::new(Network::Mainnet)
+            .encode(
+                Message::new(Network::Mainnet, Command::VerAck, Payload::VerAck),
+                &mut bytes,
+            )
+            .unwrap();
+
+        let mut codec = BitcoinCodec::new(Network::Regtest);
+        assert!(matches!(
+            codec.decode(&mut bytes),
+            Err(Error::BadMagic { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_header() {
+        let mut bytes = BytesMut::new();
+        Network::Mainnet.magic().encode(&mut bytes);
+        Command::Block.encode(&mut bytes);
+        u32::MAX.encode(&mut bytes);
+        0_u32.encode(&mut bytes);
+
+        let mut codec = BitcoinCodec::new(Network::Mainnet);
+        assert!(matches!(
+            codec.decode(&mut bytes),
+            Err(Error::MessageTooLarge {
+                command,
+                length: u32::MAX,
+                limit: MAX_PROTOCOL_MESSAGE_LENGTH,
+            }) if command == "block"
+        ));
+    }
+
+    #[test]
+    fn per_command_limit() {
+        let mut bytes = BytesMut::new();
+        BitcoinCodec::default()
+            .encode(
+                Message::new(Network::Mainnet, Command::VerAck, Payload::VerAck),
+                &mut bytes,
+            )
+            .unwrap();
+        Network::Mainnet.magic().encode(&mut bytes);
+        Command::Version.encode(&mut bytes);
+        200_u32.encode(&mut bytes);
+        0_u32.encode(&mut bytes);
+
+        let mut codec = BitcoinCodec::new(Network::Mainnet)
+            .with_limits(Limits::new().command(Command::Version, 128));
+        assert!(codec.decode(&mut bytes).unwrap().is_some());
+        assert!(matches!(
+            codec.decode(&mut bytes),
+            Err(Error::MessageTooLarge { limit: 128, .. })
+        ));
+    }
+
+    #[test]
+    fn large_block_in_chunks() {
+        use crate::p2p::bitcoin::{
+            block::Block,
+            transaction::{Transaction, TxIn, TxOut},
+        };
+
+        let transactions: Vec<Transaction> = (0..3_700)
+            .map(|i| Transaction {
+                version: i,
+                inputs: vec![TxIn::default()],
+                outputs: vec![TxOut {
+                    value: 0,
+                    script_pubkey: vec![0x6a; 1_000],
+                }],
+                ..Default::default()
+            })
+            .collect();
+        let mut block = Block {
+            transactions,
+            ..Default::default()
+        };
+        block.header.merkle_root = block.compute_merkle_root().0;
+
+        let message = Message::new(Network::Mainnet, Command::Block, Payload::Block(block));
+        let mut frame = BytesMut::new();
+        message.encode(&mut frame);
+        assert!(frame.len() > 3_900_000 && frame.len() < 4_000_000);
+
+        let mut codec = BitcoinCodec::new(Network::Mainnet);
+        let mut src = BytesMut::new();
+        let mut decoded = None;
+        for chunk in frame.chunks(64 * 1024) {
+            assert!(decoded.is_none());
+            src.extend_from_slice(chunk);
+            decoded = codec.decode(&mut src).unwrap();
+        }
+        assert_eq!(decoded, Some(message));
+    }
+}
@@ -0,0 +1,548 @@
+//! Compact block relay from [BIP152](https://github.com/bitcoin/bips/blob/master/bip-0152.mediawiki).
+//!
+//! A `cmpctblock` carries the header and 6-byte short ids of the transactions, the receiver
+//! fills in what it already has in its mempool with a [`PartialBlock`] and asks for the rest
+//! with `getblocktxn`.
+
+use super::{
+    block::{Block, BlockHeader},
+    decode::decode_list,
+    encode::{encode_list, VariableInt},
+    hashes::Hash256,
+    transaction::Transaction,
+    Decode, Encode, Error, Result,
+};
+use bytes::{Buf, BufMut, BytesMut};
+use sha2::{Digest, Sha256};
+use siphasher::sip::SipHasher24;
+use std::collections::{HashMap, HashSet};
+
+/// Compact block version announcing transactions by txid.
+pub const CMPCT_VERSION_TXID: u64 = 1;
+
+/// Compact block version announcing transactions by wtxid, the only one Bitcoin Core speaks.
+pub const CMPCT_VERSION_WTXID: u64 = 2;
+
+/// Bitcoin Core's bound on the transaction count of a block, `MAX_BLOCK_WEIGHT` over
+/// `MIN_SERIALIZABLE_TRANSACTION_WEIGHT`.
+const MAX_BLOCK_TRANSACTIONS: usize = 100_000;
+
+/// Most transactions a compact block can describe, positions are `u16` on the wire.
+const MAX_COMPACT_TRANSACTIONS: usize = u16::MAX as usize + 1;
+
+const SHORT_ID_SIZE: usize = 6;
+
+/// `sendcmpct`, asking the peer to relay blocks as `cmpctblock` of the given version.
+///
+/// With `announce` set the peer pushes new blocks without an `inv` or `headers` round trip
+/// (high-bandwidth mode).
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Encode, Decode)]
+pub struct SendCmpct {
+    pub announce: bool,
+    pub version: u64,
+}
+
+/// Lower 48 bits of the SipHash-2-4 of a transaction id, keyed per block.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct ShortId(pub u64);
+
+impl Encode for ShortId {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        buffer.put_slice(&self.0.to_le_bytes()[..SHORT_ID_SIZE]);
+        SHORT_ID_SIZE
+    }
+}
+
+impl Decode for ShortId {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        if bytes.remaining() < SHORT_ID_SIZE {
+            return Err(Error::NotEnoughBytes("short id"));
+        }
+        let mut id = [0; 8];
+        bytes.copy_to_slice(&mut id[..SHORT_ID_SIZE]);
+        Ok(ShortId(u64::from_le_bytes(id)))
+    }
+}
+
+/// Transaction sent in full inside a `cmpctblock`, at its position in the block.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PrefilledTransaction {
+    pub index: u16,
+    pub transaction: Transaction,
+}
+
+/// Payload of `cmpctblock`.
+///
+/// Prefilled indexes are absolute here, the differential encoding only exists on the wire.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HeaderAndShortIds {
+    pub header: BlockHeader,
+    pub nonce: u64,
+    pub short_ids: Vec<ShortId>,
+    pub prefilled: Vec<PrefilledTransaction>,
+}
+
+impl HeaderAndShortIds {
+    /// Compacts `block` the way Bitcoin Core does, prefilling only the coinbase.
+    pub fn from_block(block: &Block, nonce: u64, version: u64) -> Self {
+        let mut compact = HeaderAndShortIds {
+            header: block.header,
+            nonce,
+            short_ids: Vec::new(),
+            prefilled: Vec::new(),
+        };
+        let mut transactions = block.transactions.iter();
+        if let Some(coinbase) = transactions.next() {
+            compact.prefilled.push(PrefilledTransaction {
+                index: 0,
+                transaction: coinbase.clone(),
+            });
+        }
+        compact.short_ids = transactions
+            .map(|transaction| compact.short_id(&transaction_id(transaction, version)))
+            .collect();
+        compact
+    }
+
+    /// Number of transactions in the block, short ids and prefilled ones together.
+    pub fn transaction_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+
+    /// Short id of a txid (version 1) or wtxid (version 2) within this block.
+    pub fn short_id(&self, id: &Hash256) -> ShortId {
+        let (k0, k1) = self.siphash_keys();
+        let hash = SipHasher24::new_with_keys(k0, k1).hash(id.as_bytes());
+        ShortId(hash & 0xffff_ffff_ffff)
+    }
+
+    /// Keys are the first two little-endian words of a single SHA256 over header and nonce.
+    fn siphash_keys(&self) -> (u64, u64) {
+        let mut buffer = BytesMut::with_capacity(BlockHeader::SIZE + 8);
+        self.header.encode(&mut buffer);
+        self.nonce.encode(&mut buffer);
+        let digest = Sha256::digest(&buffer);
+        let k0 = u64::from_le_bytes(digest[0..8].try_into().expect("8 bytes"));
+        let k1 = u64::from_le_bytes(digest[8..16].try_into().expect("8 bytes"));
+        (k0, k1)
+    }
+}
+
+impl Encode for HeaderAndShortIds {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        let mut written = self.header.encode(buffer);
+        written += self.nonce.encode(buffer);
+        written += encode_list(&self.short_ids, buffer);
+        written += VariableInt(self.prefilled.len() as u64).encode(buffer);
+        let mut next = 0;
+        for prefilled in &self.prefilled {
+            written += VariableInt(u64::from(prefilled.index) - next).encode(buffer);
+            written += prefilled.transaction.encode(buffer);
+            next = u64::from(prefilled.index) + 1;
+        }
+        written
+    }
+}
+
+impl Decode for HeaderAndShortIds {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        let header = BlockHeader::decode(bytes)?;
+        let nonce = u64::decode(bytes)?;
+        let short_ids = decode_list(bytes, "short ids", MAX_BLOCK_TRANSACTIONS)?;
+        let count = VariableInt::decode_length(bytes, "prefilled transactions", u16::MAX as usize)?;
+        let mut prefilled = Vec::with_capacity(count);
+        let mut next = 0;
+        for _ in 0..count {
+            let index = decode_index(bytes, &mut next)?;
+            let transaction = Transaction::decode(bytes)?;
+            prefilled.push(PrefilledTransaction { index, transaction });
+        }
+        let compact = HeaderAndShortIds {
+            header,
+            nonce,
+            short_ids,
+            prefilled,
+        };
+        if compact.transaction_count() > MAX_COMPACT_TRANSACTIONS {
+            return Err(Error::InvalidCompactBlock("too many transactions"));
+        }
+        Ok(compact)
+    }
+}
+
+/// Payload of `getblocktxn`, the positions of the transactions a [`PartialBlock`] is missing.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BlockTransactionsRequest {
+    pub block_hash: Hash256,
+    pub indexes: Vec<u16>,
+}
+
+impl Encode for BlockTransactionsRequest {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        let mut written = self.block_hash.encode(buffer);
+        written += VariableInt(self.indexes.len() as u64).encode(buffer);
+        let mut next = 0;
+        for index in &self.indexes {
+            written += VariableInt(u64::from(*index) - next).encode(buffer);
+            next = u64::from(*index) + 1;
+        }
+        written
+    }
+}
+
+impl Decode for BlockTransactionsRequest {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        let block_hash = Hash256::decode(bytes)?;
+        let count =
+            VariableInt::decode_length(bytes, "getblocktxn indexes", MAX_BLOCK_TRANSACTIONS)?;
+        let mut next = 0;
+        let indexes = (0..count)
+            .map(|_| decode_index(bytes, &mut next))
+            .collect::<Result<_>>()?;
+        Ok(BlockTransactionsRequest {
+            block_hash,
+            indexes,
+        })
+    }
+}
+
+/// Payload of `blocktxn`, the transactions asked for in a `getblocktxn`, in the same order.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BlockTransactions {
+    pub block_hash: Hash256,
+    pub transactions: Vec<Transaction>,
+}
+
+impl Encode for BlockTransactions {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        self.block_hash.encode(buffer) + encode_list(&self.transactions, buffer)
+    }
+}
+
+impl Decode for BlockTransactions {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        let block_hash = Hash256::decode(bytes)?;
+        let transactions = decode_list(bytes, "blocktxn transactions", MAX_BLOCK_TRANSACTIONS)?;
+        Ok(BlockTransactions {
+            block_hash,
+            transactions,
+        })
+    }
+}
+
+/// Reads a differentially encoded index, `next` is one past the previous index.
+fn decode_index(bytes: &mut BytesMut, next: &mut u64) -> Result<u16> {
+    let index = VariableInt::decode(bytes)?
+        .0
+        .checked_add(*next)
+        .filter(|index| *index <= u64::from(u16::MAX))
+        .ok_or(Error::InvalidCompactBlock("differential index overflow"))?;
+    *next = index + 1;
+    Ok(index as u16)
+}
+
+fn transaction_id(transaction: &Transaction, version: u64) -> Hash256 {
+    if version >= CMPCT_VERSION_WTXID {
+        transaction.wtxid()
+    } else {
+        transaction.txid()
+    }
+}
+
+/// Block being rebuilt from a `cmpctblock` and the transactions we already know about.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PartialBlock {
+    header: BlockHeader,
+    transactions: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    /// Places the prefilled transactions and matches `mempool` against the short ids.
+    ///
+    /// Mempool transactions whose short ids collide are left out, they get requested like any
+    /// other missing one. Duplicate short ids in the block itself make it impossible to
+    /// reconstruct, the full block has to be fetched with `getdata` instead.
+    pub fn new<'a>(
+        compact: &HeaderAndShortIds,
+        version: u64,
+        mempool: impl IntoIterator<Item = &'a Transaction>,
+    ) -> Result<Self> {
+        let count = compact.transaction_count();
+        if count == 0 {
+            return Err(Error::InvalidCompactBlock("no transactions"));
+        }
+        if count > MAX_COMPACT_TRANSACTIONS {
+            return Err(Error::InvalidCompactBlock("too many transactions"));
+        }
+        let mut transactions = vec![None; count];
+        for prefilled in &compact.prefilled {
+            let slot = transactions
+                .get_mut(prefilled.index as usize)
+                .ok_or(Error::InvalidCompactBlock("prefilled index out of range"))?;
+            *slot = Some(prefilled.transaction.clone());
+        }
+
+        let mut slots = HashMap::with_capacity(compact.short_ids.len());
+        let mut empty = transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, transaction)| transaction.is_none())
+            .map(|(index, _)| index);
+        for short_id in &compact.short_ids {
+            let index = empty.next().expect("one empty slot per short id");
+            if slots.insert(*short_id, index).is_some() {
+                return Err(Error::InvalidCompactBlock("duplicate short ids"));
+            }
+        }
+
+        let mut matched = HashMap::new();
+        let mut collided = HashSet::new();
+        for transaction in mempool {
+            let id = transaction_id(transaction, version);
+            let Some(&index) = slots.get(&compact.short_id(&id)) else {
+                continue;
+            };
+            if collided.contains(&index) {
+                continue;
+            }
+            match matched.insert(index, id) {
+                Some(previous) if previous != id => {
+                    tracing::debug!("Short id collision at index {index}");
+                    collided.insert(index);
+                    transactions[index] = None;
+                }
+                _ => transactions[index] = Some(transaction.clone()),
+            }
+        }
+
+        Ok(PartialBlock {
+            header: compact.header,
+            transactions,
+        })
+    }
+
+    pub fn block_hash(&self) -> Hash256 {
+        self.header.hash()
+    }
+
+    /// Positions of the transactions still missing.
+    pub fn missing(&self) -> Vec<u16> {
+        self.transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, transaction)| transaction.is_none())
+            .map(|(index, _)| u16::try_from(index).expect("new caps the transaction count"))
+            .collect()
+    }
+
+    /// `getblocktxn` for the missing transactions, `None` once nothing is missing.
+    pub fn request(&self) -> Option<BlockTransactionsRequest> {
+        let indexes = self.missing();
+        if indexes.is_empty() {
+            return None;
+        }
+        Some(BlockTransactionsRequest {
+            block_hash: self.block_hash(),
+            indexes,
+        })
+    }
+
+    /// Fills the gaps with a `blocktxn` answering [`PartialBlock::request`].
+    pub fn fill(&mut self, response: BlockTransactions) -> Result<()> {
+        if response.block_hash != self.block_hash() {
+            return Err(Error::InvalidCompactBlock("blocktxn for another block"));
+        }
+        let missing = self.missing();
+        if response.transactions.len() != missing.len() {
+            return Err(Error::InvalidCompactBlock(
+                "blocktxn does not match the missing transactions",
+            ));
+        }
+        for (index, transaction) in missing.into_iter().zip(response.transactions) {
+            self.transactions[index as usize] = Some(transaction);
+        }
+        Ok(())
+    }
+
+    /// Completed block, checked against the merkle root in its header.
+    ///
+    /// A mismatch may also be an undetected short id collision, so the caller should fall back
+    /// to fetching the full block rather than ban the peer.
+    pub fn into_block(self) -> Result<Block> {
+        let transactions = self
+            .transactions
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .ok_or(Error::InvalidCompactBlock("transactions still missing"))?;
+        let block = Block {
+            header: self.header,
+            transactions,
+        };
+        block.check_merkle_root()?;
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::bitcoin::transaction::{OutPoint, TxIn, TxOut};
+
+    fn block(count: i32) -> Block {
+        let transactions = (0..count)
+            .map(|i| Transaction {
+                version: 2,
+                inputs: vec![TxIn {
+                    previous_output: if i == 0 {
+                        OutPoint::NULL
+                    } else {
+                        OutPoint::default()
+                    },
+                    script_sig: i.to_le_bytes().to_vec(),
+                    sequence: u32::MAX,
+                    witness: vec![vec![0x51]],
+                }],
+                outputs: vec![TxOut {
+                    value: 50,
+                    script_pubkey: vec![0x51],
+                }],
+                lock_time: 0,
+            })
+            .collect();
+        let mut block = Block {
+            transactions,
+            ..Default::default()
+        };
+        block.header.merkle_root = block.compute_merkle_root().0;
+        block
+    }
+
+    fn roundtrip<T: Encode + Decode>(value: &T) -> (BytesMut, T) {
+        let mut buffer = BytesMut::new();
+        value.encode(&mut buffer);
+        let encoded = buffer.clone();
+        let decoded = T::decode(&mut buffer).unwrap();
+        assert!(buffer.is_empty());
+        (encoded, decoded)
+    }
+
+    #[test]
+    fn sendcmpct_wire_format() {
+        let sendcmpct = SendCmpct {
+            announce: true,
+            version: CMPCT_VERSION_WTXID,
+        };
+        let (encoded, decoded) = roundtrip(&sendcmpct);
+        assert_eq!(&encoded[..], &[1, 2, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(decoded, sendcmpct);
+    }
+
+    #[test]
+    fn differential_indexes() {
+        let block = block(6);
+        let mut compact = HeaderAndShortIds::from_block(&block, 42, CMPCT_VERSION_WTXID);
+        compact.short_ids.remove(1);
+        compact.prefilled.push(PrefilledTransaction {
+            index: 2,
+            transaction: block.transactions[2].clone(),
+        });
+        let (encoded, decoded) = roundtrip(&compact);
+        assert_eq!(decoded, compact);
+        // Prefilled count, then index 0 as 0 and index 2 as 2 - 0 - 1
+        let prefilled = BlockHeader::SIZE + 8 + 1 + 4 * SHORT_ID_SIZE;
+        assert_eq!(&encoded[prefilled..prefilled + 2], &[2, 0]);
+
+        let request = BlockTransactionsRequest {
+            block_hash: block.hash(),
+            indexes: vec![1, 2, 5, 300],
+        };
+        let (encoded, decoded) = roundtrip(&request);
+        assert_eq!(decoded, request);
+        assert_eq!(&encoded[32..], &[4, 1, 0, 2, 0xfd, 0x26, 0x01]);
+    }
+
+    #[test]
+    fn index_overflow() {
+        let mut bytes = BytesMut::new();
+        Hash256::ZERO.encode(&mut bytes);
+        VariableInt(2).encode(&mut bytes);
+        VariableInt(u16::MAX as u64).encode(&mut bytes);
+        VariableInt(0).encode(&mut bytes);
+        assert!(matches!(
+            BlockTransactionsRequest::decode(&mut bytes),
+            Err(Error::InvalidCompactBlock(_))
+        ));
+    }
+
+    #[test]
+    fn too_many_transactions() {
+        let block = block(1);
+        let mut compact = HeaderAndShortIds::from_block(&block, 42, CMPCT_VERSION_WTXID);
+        compact.prefilled.clear();
+        compact.short_ids = (0..=u16::MAX as u64 + 1).map(ShortId).collect();
+        assert_eq!(compact.transaction_count(), MAX_COMPACT_TRANSACTIONS + 1);
+        let mut bytes = BytesMut::new();
+        compact.encode(&mut bytes);
+        assert!(matches!(
+            HeaderAndShortIds::decode(&mut bytes),
+            Err(Error::InvalidCompactBlock("too many transactions"))
+        ));
+        assert!(matches!(
+            PartialBlock::new(&compact, CMPCT_VERSION_WTXID, []),
+            Err(Error::InvalidCompactBlock("too many transactions"))
+        ));
+
+        compact.short_ids.pop();
+        let partial = PartialBlock::new(&compact, CMPCT_VERSION_WTXID, []).unwrap();
+        assert_eq!(partial.missing().last(), Some(&u16::MAX));
+    }
+
+    #[test]
+    fn reconstruct_with_blocktxn() {
+        let block = block(8);
+        let compact = HeaderAndShortIds::from_block(&block, 7, CMPCT_VERSION_WTXID);
+        assert_eq!(compact.short_ids.len(), 7);
+
+        let unrelated = self::block(12).transactions.split_off(10);
+        let mempool: Vec<_> = [1, 2, 4, 5, 7]
+            .iter()
+            .map(|i| &block.transactions[*i])
+            .chain(&unrelated)
+            .collect();
+        let mut partial = PartialBlock::new(&compact, CMPCT_VERSION_WTXID, mempool).unwrap();
+        let request = partial.request().unwrap();
+        assert_eq!(request.indexes, vec![3, 6]);
+        assert_eq!(request.block_hash, block.hash());
+
+        let response = BlockTransactions {
+            block_hash: block.hash(),
+            transactions: vec![block.transactions[3].clone(), block.transactions[6].clone()],
+        };
+        partial.fill(response).unwrap();
+        assert!(partial.request().is_none());
+        assert_eq!(partial.into_block().unwrap(), block);
+    }
+
+    #[test]
+    fn version_selects_id() {
+        let block = block(3);
+        let compact = HeaderAndShortIds::from_block(&block, 0, CMPCT_VERSION_TXID);
+        let partial = PartialBlock::new(&compact, CMPCT_VERSION_TXID, &block.transactions).unwrap();
+        assert!(partial.missing().is_empty());
+        // The witnesses make wtxids differ, so a version 2 lookup finds nothing
+        let partial =
+            PartialBlock::new(&compact, CMPCT_VERSION_WTXID, &block.transactions).unwrap();
+        assert_eq!(partial.missing(), vec![1, 2]);
+    }
+
+    #[test]
+    fn duplicate_short_ids() {
+        let block = block(3);
+        let mut compact = HeaderAndShortIds::from_block(&block, 0, CMPCT_VERSION_WTXID);
+        compact.short_ids[1] = compact.short_ids[0];
+        assert!(matches!(
+            PartialBlock::new(&compact, CMPCT_VERSION_WTXID, []),
+            Err(Error::InvalidCompactBlock(_))
+        ));
+    }
+}
@@ -0,0 +1,352 @@
+use super::{
+    codec::Limits,
+    network::Network,
+    nonce::NonceRegistry,
+    peer::Features,
+    protocol::{Address, VersionMessage},
+    services::{ServiceFlags, NODE_NONE, NODE_P2P_V2},
+};
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Protocol version we speak by default (wtxidrelay, BIP339).
+pub const PROTOCOL_VERSION: i32 = 70016;
+
+/// First version to understand wtxidrelay (BIP339), also used as the cut-off for sendaddrv2.
+pub const WTXID_RELAY_VERSION: i32 = 70016;
+
+/// First version to understand compact blocks (BIP152).
+pub const SHORT_IDS_BLOCKS_VERSION: i32 = 70014;
+
+/// Oldest peer version we talk to by default, same as Bitcoin Core.
+pub const MIN_PEER_PROTO_VERSION: i32 = 31800;
+
+/// Time allowed to establish the TCP connection.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time allowed from the established connection to the peer's verack.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Silence after which a ready connection is dropped.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(20 * 60);
+
+/// Time a keepalive ping may go unanswered, Bitcoin Core's `TIMEOUT_INTERVAL`.
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(20 * 60);
+
+/// Settings for the `version` message sent by [`Handshake`](super::Handshake).
+///
+/// Fields left unset fall back to values computed at connection time: the current UNIX time,
+/// a random nonce and the actual remote address.
+#[derive(Debug, Clone)]
+pub struct HandshakeConfig {
+    pub(super) network: Network,
+    pub(super) limits: Limits,
+    pub(super) connect_timeout: Duration,
+    pub(super) handshake_timeout: Duration,
+    pub(super) idle_timeout: Duration,
+    pub(super) cancellation: CancellationToken,
+    pub(super) keepalive: Option<Duration>,
+    pub(super) ping_timeout: Duration,
+    pub(super) compact_blocks: Option<bool>,
+    pub(super) v2_transport: bool,
+    peer_services: Option<ServiceFlags>,
+    pub(super) required_services: ServiceFlags,
+    pub(super) required_features: Features,
+    pub(super) min_peer_version: i32,
+    pub(super) nonce_registry: NonceRegistry,
+    version: i32,
+    services: ServiceFlags,
+    timestamp: Option<i64>,
+    addr_recv: Option<SocketAddr>,
+    addr_from: Option<SocketAddr>,
+    pub(super) nonce: Option<u64>,
+    user_agent: String,
+    start_height: i32,
+    relay: bool,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        Self {
+            network: Network::default(),
+            limits: Limits::default(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            cancellation: CancellationToken::new(),
+            keepalive: None,
+            ping_timeout: DEFAULT_PING_TIMEOUT,
+            compact_blocks: None,
+            v2_transport: false,
+            peer_services: None,
+            required_services: NODE_NONE,
+            required_features: Features::default(),
+            min_peer_version: MIN_PEER_PROTO_VERSION,
+            nonce_registry: NonceRegistry::new(),
+            version: PROTOCOL_VERSION,
+            services: NODE_NONE,
+            timestamp: None,
+            addr_recv: None,
+            addr_from: None,
+            nonce: None,
+            user_agent: "/ramen/".into(),
+            start_height: 0,
+            relay: false,
+        }
+    }
+}
+
+impl HandshakeConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn network(mut self, network: Network) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Message size caps applied to everything the peer sends.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Bounds the v2 key exchange and the version/verack exchange together.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Disconnects a ready peer once nothing was received for `timeout`.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Cancelling `token` aborts handshakes in progress with
+    /// [`HandshakeError::Cancelled`](super::HandshakeError::Cancelled) and closes established
+    /// connections. One token can be shared by any number of connections.
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    /// Sends a ping every `interval` once the handshake is done, see [`Handshake::latency`].
+    ///
+    /// [`Handshake::latency`]: super::Handshake::latency
+    pub fn keepalive(mut self, interval: Duration) -> Self {
+        self.keepalive = Some(interval);
+        self
+    }
+
+    /// Disconnects a peer that leaves a keepalive ping unanswered for `timeout`, however busy it
+    /// keeps the connection otherwise.
+    pub fn ping_timeout(mut self, timeout: Duration) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// Sends `sendcmpct` version 2 once the peer's verack arrives, `announce` asks for
+    /// high-bandwidth mode where new blocks are pushed without announcing them first.
+    ///
+    /// Without this compact blocks can still be negotiated later by sending
+    /// [`Payload::SendCmpct`](super::protocol::Payload::SendCmpct) on the split channels.
+    pub fn compact_blocks(mut self, announce: bool) -> Self {
+        self.compact_blocks = Some(announce);
+        self
+    }
+
+    /// Allows the BIP324 encrypted transport and advertises `NODE_P2P_V2` in our version.
+    ///
+    /// Outbound, it is only attempted when [`peer_services`](Self::peer_services) says the peer
+    /// supports it; a connection that fails during the key exchange is retried in plaintext.
+    /// Inbound, both v1 and v2 peers are accepted.
+    pub fn v2_transport(mut self, enabled: bool) -> Self {
+        self.v2_transport = enabled;
+        self
+    }
+
+    /// Services the peer is known to offer, usually learned from an `addr` message.
+    pub fn peer_services(mut self, services: ServiceFlags) -> Self {
+        self.peer_services = Some(services);
+        self
+    }
+
+    /// Services the peer must advertise in its version, peers lacking any of them are
+    /// disconnected and the handshake fails.
+    pub fn required_services(mut self, services: ServiceFlags) -> Self {
+        self.required_services = services;
+        self
+    }
+
+    /// Features the peer must negotiate before its verack, peers lacking any of them are
+    /// disconnected.
+    ///
+    /// Only `wtxid_relay` and `addr_v2` are settled by then, the other fields are ignored.
+    pub fn required_features(mut self, features: Features) -> Self {
+        self.required_features = features;
+        self
+    }
+
+    /// Disconnects peers announcing an older protocol version, defaults to
+    /// [`MIN_PEER_PROTO_VERSION`].
+    pub fn min_peer_version(mut self, version: i32) -> Self {
+        self.min_peer_version = version;
+        self
+    }
+
+    /// Shares the registry used to detect connections to ourselves with other configs.
+    ///
+    /// Clones of a config already share one, this is only needed to tie together configs built
+    /// separately, say the one of a [`Listener`](super::Listener) and the one used for dialing.
+    pub fn nonce_registry(mut self, registry: NonceRegistry) -> Self {
+        self.nonce_registry = registry;
+        self
+    }
+
+    /// Whether the connection should start with a v2 key exchange.
+    pub(super) fn wants_v2(&self) -> bool {
+        self.v2_transport
+            && self
+                .peer_services
+                .is_some_and(|services| services.contains(NODE_P2P_V2))
+    }
+
+    pub fn version(mut self, version: i32) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Services we advertise, both in the message and in `addr_from`.
+    pub fn services(mut self, services: ServiceFlags) -> Self {
+        self.services = services;
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: i64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Overrides the address reported as the peer's, which defaults to the connected socket.
+    pub fn addr_recv(mut self, addr: SocketAddr) -> Self {
+        self.addr_recv = Some(addr);
+        self
+    }
+
+    pub fn addr_from(mut self, addr: SocketAddr) -> Self {
+        self.addr_from = Some(addr);
+        self
+    }
+
+    /// Fixed nonce for our version messages, zero picks a random one like the default.
+    pub fn nonce(mut self, nonce: u64) -> Self {
+        self.nonce = Some(nonce);
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    pub fn start_height(mut self, start_height: i32) -> Self {
+        self.start_height = start_height;
+        self
+    }
+
+    pub fn relay(mut self, relay: bool) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    /// Builds the `version` message for a connection to `remote`.
+    pub fn version_message(&self, remote: SocketAddr) -> VersionMessage {
+        let unspecified = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+        let addr_recv = self.addr_recv.unwrap_or(remote);
+        let addr_from = self.addr_from.unwrap_or(unspecified);
+        let timestamp = self.timestamp.unwrap_or_else(unix_time);
+        let services = if self.v2_transport {
+            self.services | NODE_P2P_V2
+        } else {
+            self.services
+        };
+
+        VersionMessage {
+            version: self.version,
+            services,
+            timestamp,
+            addr_recv: Address {
+                time: (),
+                services: NODE_NONE,
+                ip: addr_recv.ip(),
+                port: addr_recv.port().into(),
+            },
+            addr_from: Address {
+                time: (),
+                services,
+                ip: addr_from.ip(),
+                port: addr_from.port().into(),
+            },
+            nonce: self.nonce.unwrap_or_else(rand::random),
+            user_agent: self.user_agent.as_str().into(),
+            start_height: self.start_height,
+            relay: Some(self.relay),
+        }
+    }
+}
+
+/// Current UNIX time in seconds, as used in `version` messages.
+pub(super) fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::bitcoin::NODE_NETWORK;
+
+    #[test]
+    fn version_message_defaults() {
+        let remote: SocketAddr = "203.0.113.7:8333".parse().unwrap();
+        let version = HandshakeConfig::new()
+            .services(1033.into())
+            .start_height(840_000)
+            .version_message(remote);
+
+        assert_eq!(version.version, PROTOCOL_VERSION);
+        assert_eq!(version.addr_recv.ip, remote.ip());
+        assert_eq!(version.addr_recv.port, remote.port().into());
+        assert_eq!(version.addr_from.services, 1033.into());
+        assert_eq!(version.start_height, 840_000);
+        assert!(version.timestamp > 0);
+    }
+
+    #[test]
+    fn v2_needs_peer_support() {
+        let config = HandshakeConfig::new().v2_transport(true);
+        assert!(!config.wants_v2());
+        assert!(!config.clone().peer_services(NODE_NETWORK).wants_v2());
+        assert!(config
+            .clone()
+            .peer_services(NODE_P2P_V2 | NODE_NETWORK)
+            .wants_v2());
+
+        let remote: SocketAddr = "203.0.113.7:8333".parse().unwrap();
+        let version = config.services(NODE_NETWORK).version_message(remote);
+        assert_eq!(version.services, NODE_P2P_V2 | NODE_NETWORK);
+        assert_eq!(version.addr_from.services, NODE_P2P_V2 | NODE_NETWORK);
+    }
+}
@@ -1,9 +1,15 @@
-use super::error::Error;
-use bytes::{Buf, BytesMut};
+use super::{encode::VariableInt, error::Error};
+use bytes::{Buf, Bytes, BytesMut};
 use std::net::IpAddr;
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// Bitcoin Core's `MAX_SIZE`, the upper bound for any compact-size length prefix.
+pub const MAX_SIZE: usize = 0x0200_0000;
+
+/// Bitcoin Core's `MAX_VECTOR_ALLOCATE`, how much a decoded list reserves up front.
+const MAX_VECTOR_ALLOCATE: usize = 5_000_000;
+
 pub trait Decode
 where
     Self: Sized,
@@ -60,3 +66,126 @@ impl Decode for IpAddr {
         Ok(ip)
     }
 }
+
+impl VariableInt {
+    /// Decodes a length prefix, refusing anything above `limit` (and [`MAX_SIZE`]) so callers
+    /// can allocate for it safely.
+    pub(super) fn decode_length(
+        bytes: &mut BytesMut,
+        what: &'static str,
+        limit: usize,
+    ) -> Result<usize> {
+        let length = Self::decode(bytes)?.0;
+        let limit = limit.min(MAX_SIZE);
+        if length > limit as u64 {
+            return Err(Error::LengthTooLarge {
+                what,
+                length,
+                limit,
+            });
+        }
+        Ok(length as usize)
+    }
+}
+
+impl Decode for VariableInt {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        if bytes.remaining() < 1 {
+            return Err(Error::NotEnoughBytes("variable int"));
+        }
+        match bytes.get_u8() {
+            0xFD => {
+                let number = u16::decode(bytes)?;
+                Ok(VariableInt(number as u64))
+            }
+            0xFE => {
+                let number = u32::decode(bytes)?;
+                Ok(VariableInt(number as u64))
+            }
+            0xFF => {
+                let number = u64::decode(bytes)?;
+                Ok(VariableInt(number))
+            }
+            x => Ok(VariableInt(x as u64)),
+        }
+    }
+}
+
+/// Decodes a compact-size count followed by that many items, at most `limit` of them.
+pub fn decode_list<T: Decode>(
+    bytes: &mut BytesMut,
+    what: &'static str,
+    limit: usize,
+) -> Result<Vec<T>> {
+    let count = VariableInt::decode_length(bytes, what, limit)?;
+    // The count is the peer's claim, only reserve what a few MB hold until the items show up
+    let reserve = MAX_VECTOR_ALLOCATE / std::mem::size_of::<T>().max(1);
+    let mut items = Vec::with_capacity(count.min(reserve));
+    for _ in 0..count {
+        items.push(T::decode(bytes)?);
+    }
+    Ok(items)
+}
+
+/// Decodes a compact-size prefixed byte string of at most `limit` bytes.
+pub(super) fn decode_bytes(
+    bytes: &mut BytesMut,
+    what: &'static str,
+    limit: usize,
+) -> Result<Vec<u8>> {
+    let length = VariableInt::decode_length(bytes, what, limit)?;
+    if bytes.remaining() < length {
+        return Err(Error::NotEnoughBytes(what));
+    }
+    Ok(bytes.split_to(length).to_vec())
+}
+
+/// At most [`MAX_SIZE`] items, wrap the field with `#[wire(var_int, max = ...)]` for a tighter
+/// cap.
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(buffer: &mut BytesMut) -> Result<Self> {
+        decode_list(buffer, "vector", MAX_SIZE)
+    }
+}
+
+impl<const N: usize> Decode for [u8; N] {
+    fn decode(buffer: &mut BytesMut) -> Result<Self> {
+        if buffer.remaining() < N {
+            return Err(Error::NotEnoughBytes("byte array"));
+        }
+        let mut array = [0; N];
+        buffer.copy_to_slice(&mut array);
+        Ok(array)
+    }
+}
+
+/// At most [`MAX_SIZE`] bytes.
+impl Decode for Bytes {
+    fn decode(buffer: &mut BytesMut) -> Result<Self> {
+        Ok(decode_bytes(buffer, "byte string", MAX_SIZE)?.into())
+    }
+}
+
+/// `None` once the buffer is exhausted, for trailing fields older peers leave out.
+impl<T: Decode> Decode for Option<T> {
+    fn decode(buffer: &mut BytesMut) -> Result<Self> {
+        if !buffer.has_remaining() {
+            return Ok(None);
+        }
+        T::decode(buffer).map(Some)
+    }
+}
+
+macro_rules! make_tuple_decoder {
+    ($($name: ident),+) => {
+        impl<$($name: Decode),+> Decode for ($($name,)+) {
+            fn decode(buffer: &mut BytesMut) -> Result<Self> {
+                Ok(($($name::decode(buffer)?,)+))
+            }
+        }
+    };
+}
+
+make_tuple_decoder!(A, B);
+make_tuple_decoder!(A, B, C);
+make_tuple_decoder!(A, B, C, D);
@@ -1,4 +1,4 @@
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use std::net::IpAddr;
 
 pub trait Encode {
@@ -47,3 +47,89 @@ impl Encode for IpAddr {
         16
     }
 }
+
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub(super) struct VariableInt(pub(super) u64);
+
+impl Encode for VariableInt {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        match self.0 {
+            0..=0xFC => {
+                buffer.put_u8(self.0 as u8);
+                1
+            }
+            0xFD..=0xFFFF => {
+                buffer.put_u8(0xFD);
+                1 + (self.0 as u16).encode(buffer)
+            }
+            0x10000..=0xFFFFFFFF => {
+                buffer.put_u8(0xFE);
+                1 + (self.0 as u32).encode(buffer)
+            }
+            _ => {
+                buffer.put_u8(0xFF);
+                1 + self.0.encode(buffer)
+            }
+        }
+    }
+}
+
+/// Encodes `items` behind a compact-size count.
+pub fn encode_list<T: Encode>(items: &[T], buffer: &mut BytesMut) -> usize {
+    items.iter().fold(
+        VariableInt(items.len() as u64).encode(buffer),
+        |written, item| written + item.encode(buffer),
+    )
+}
+
+/// Encodes a byte string behind its compact-size length.
+pub(super) fn encode_bytes(data: &[u8], buffer: &mut BytesMut) -> usize {
+    let written = VariableInt(data.len() as u64).encode(buffer);
+    buffer.put_slice(data);
+    written + data.len()
+}
+
+/// Compact-size count followed by the items.
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        encode_list(self, buffer)
+    }
+}
+
+/// Raw bytes, the length is implied by the type.
+impl<const N: usize> Encode for [u8; N] {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        buffer.put_slice(self);
+        N
+    }
+}
+
+/// Compact-size length followed by the bytes.
+impl Encode for Bytes {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        encode_bytes(self, buffer)
+    }
+}
+
+/// Nothing for `None`, for trailing fields older peers leave out.
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        self.as_ref().map_or(0, |value| value.encode(buffer))
+    }
+}
+
+macro_rules! make_tuple_encoder {
+    ($($name: ident),+) => {
+        impl<$($name: Encode),+> Encode for ($($name,)+) {
+            fn encode(&self, buffer: &mut BytesMut) -> usize {
+                #[allow(non_snake_case)]
+                let ($($name,)+) = self;
+                0 $(+ $name.encode(buffer))+
+            }
+        }
+    };
+}
+
+make_tuple_encoder!(A, B);
+make_tuple_encoder!(A, B, C);
+make_tuple_encoder!(A, B, C, D);
@@ -1,3 +1,5 @@
+use super::{hashes::Hash256, services::ServiceFlags};
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, thiserror::Error)]
@@ -6,10 +8,94 @@ pub enum Error {
     IO(#[from] std::io::Error),
     #[error("utf8 error: {0}")]
     Utf8(#[from] std::str::Utf8Error),
+    #[error("hex error: {0}")]
+    Hex(#[from] hex::FromHexError),
     #[error("array error: {0}")]
     TryFromSlice(#[from] std::array::TryFromSliceError),
-    #[error("command error: {0}")]
-    Command(String),
     #[error("not enough bytes to decode: {0}")]
     NotEnoughBytes(&'static str),
+    #[error("bad magic: expected {expected:#010x}, got {actual:#010x}")]
+    BadMagic { expected: u32, actual: u32 },
+    #[error("checksum mismatch: expected {expected:#010x}, got {actual:#010x}")]
+    ChecksumMismatch { expected: u32, actual: u32 },
+    #[error("payload length mismatch: header says {expected} bytes, decoded {actual}")]
+    PayloadLength { expected: u32, actual: u32 },
+    #[error("{command} payload of {length} bytes exceeds the limit of {limit}")]
+    MessageTooLarge {
+        command: String,
+        length: u32,
+        limit: u32,
+    },
+    #[error("{what} length {length} exceeds the limit of {limit}")]
+    LengthTooLarge {
+        what: &'static str,
+        length: u64,
+        limit: usize,
+    },
+    #[error("invalid address of {length} bytes for network id {id}")]
+    InvalidAddress { id: u8, length: usize },
+    #[error("invalid transaction: {0}")]
+    InvalidTransaction(&'static str),
+    #[error("merkle root mismatch: header commits to {expected}, transactions hash to {actual}")]
+    MerkleRootMismatch { expected: Hash256, actual: Hash256 },
+    #[error("invalid block: {0}")]
+    InvalidBlock(&'static str),
+    #[error("invalid merkle block: {0}")]
+    InvalidMerkleBlock(&'static str),
+    #[error("invalid compact block: {0}")]
+    InvalidCompactBlock(&'static str),
+    #[error("invalid filter: {0}")]
+    InvalidFilter(&'static str),
+    #[error("filter header mismatch at height {height}: expected {expected}, got {actual}")]
+    FilterHeaderMismatch {
+        height: u32,
+        expected: Hash256,
+        actual: Hash256,
+    },
+    #[error("invalid header {hash}: {reason}")]
+    InvalidHeader { hash: Hash256, reason: &'static str },
+    #[error("header {hash} does not connect to a known header {prev}")]
+    UnconnectedHeader { hash: Hash256, prev: Hash256 },
+    #[error("v2 transport: {0}")]
+    V2Transport(&'static str),
+    #[error("unknown network: {0}")]
+    UnknownNetwork(String),
+    #[error("protocol violation: {0}")]
+    ProtocolViolation(&'static str),
+}
+
+/// Why [`Handshake::connect`](super::Handshake::connect) or
+/// [`Handshake::accept`](super::Handshake::accept) failed.
+#[derive(Debug, thiserror::Error)]
+pub enum HandshakeError {
+    #[error("io error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("{0} timed out")]
+    Timeout(&'static str),
+    #[error("cancelled")]
+    Cancelled,
+    #[error("peer disconnected during the handshake")]
+    PeerDisconnected,
+    #[error("protocol violation: {0}")]
+    ProtocolViolation(Error),
+    #[error("peer version {version} is older than the minimum {minimum}")]
+    VersionTooOld { version: i32, minimum: i32 },
+    #[error("connected to ourselves")]
+    SelfConnection,
+    #[error("peer offers {offered} but {required} are required")]
+    MissingServices {
+        required: ServiceFlags,
+        offered: ServiceFlags,
+    },
+    #[error("peer did not negotiate {0}")]
+    MissingFeature(&'static str),
+}
+
+impl From<Error> for HandshakeError {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::IO(e) => Self::IO(e),
+            e => Self::ProtocolViolation(e),
+        }
+    }
 }
@@ -0,0 +1,660 @@
+//! Compact block filters, the Golomb-coded sets from
+//! [BIP158](https://github.com/bitcoin/bips/blob/master/bip-0158.mediawiki) and the messages
+//! serving them from [BIP157](https://github.com/bitcoin/bips/blob/master/bip-0157.mediawiki).
+//!
+//! Filter headers commit to every filter before them, a client fetches `cfcheckpt` from several
+//! peers, fills the gaps with `cfheaders` and then only trusts `cfilter`s hashing to a verified
+//! header.
+
+use super::{
+    block::Block,
+    chain::HeaderChain,
+    codec::MAX_PROTOCOL_MESSAGE_LENGTH,
+    decode::{decode_bytes, decode_list},
+    encode::{encode_bytes, encode_list, VariableInt},
+    hashes::Hash256,
+    Decode, Encode, Error, Result,
+};
+use bytes::BytesMut;
+use siphasher::sip::SipHasher24;
+use std::collections::BTreeSet;
+
+/// The only filter type defined so far, see [`BlockFilter::basic`].
+pub const BASIC_FILTER: u8 = 0;
+
+/// Bitcoin Core's `MAX_GETCFILTERS_SIZE`, the most filters one `getcfilters` may ask for.
+pub const MAX_GETCFILTERS_SIZE: u32 = 1000;
+
+/// Bitcoin Core's `MAX_GETCFHEADERS_SIZE`, the most filter hashes a `cfheaders` may carry.
+pub const MAX_GETCFHEADERS_SIZE: usize = 2000;
+
+/// Distance between two filter headers in a `cfcheckpt`.
+pub const CHECKPOINT_INTERVAL: u32 = 1000;
+
+/// Golomb-Rice parameter of basic filters.
+const BASIC_P: u8 = 19;
+
+/// False positive rate of basic filters is one in `BASIC_M`.
+const BASIC_M: u64 = 784_931;
+
+/// Request for filters of the blocks from `start_height` up to `stop_hash`, used both by
+/// `getcfilters` and `getcfheaders`.
+#[derive(Debug, Clone, Eq, PartialEq, Encode, Decode)]
+pub struct FilterRequest {
+    pub filter_type: u8,
+    pub start_height: u32,
+    pub stop_hash: Hash256,
+}
+
+/// Payload of `cfilter`, one filter per block asked for in a `getcfilters`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CFilter {
+    pub filter_type: u8,
+    pub block_hash: Hash256,
+    pub filter: BlockFilter,
+}
+
+impl Encode for CFilter {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        let mut written = self.filter_type.encode(buffer);
+        written += self.block_hash.encode(buffer);
+        written += encode_bytes(&self.filter.content, buffer);
+        written
+    }
+}
+
+impl Decode for CFilter {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        let filter_type = u8::decode(bytes)?;
+        let block_hash = Hash256::decode(bytes)?;
+        let content = decode_bytes(bytes, "filter", MAX_PROTOCOL_MESSAGE_LENGTH as usize)?;
+        Ok(CFilter {
+            filter_type,
+            block_hash,
+            filter: BlockFilter { content },
+        })
+    }
+}
+
+/// Payload of `cfheaders`, the filter hashes of a range of blocks ending at `stop_hash`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CFHeaders {
+    pub filter_type: u8,
+    pub stop_hash: Hash256,
+    /// Filter header of the block before the first one in the range
+    pub previous_filter_header: Hash256,
+    pub filter_hashes: Vec<Hash256>,
+}
+
+impl CFHeaders {
+    /// Filter headers of the range, chained from `previous_filter_header`.
+    pub fn filter_headers(&self) -> Vec<Hash256> {
+        self.filter_hashes
+            .iter()
+            .scan(self.previous_filter_header, |previous, hash| {
+                *previous = filter_header(hash, previous);
+                Some(*previous)
+            })
+            .collect()
+    }
+}
+
+impl Encode for CFHeaders {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        let mut written = self.filter_type.encode(buffer);
+        written += self.stop_hash.encode(buffer);
+        written += self.previous_filter_header.encode(buffer);
+        written += encode_list(&self.filter_hashes, buffer);
+        written
+    }
+}
+
+impl Decode for CFHeaders {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        let filter_type = u8::decode(bytes)?;
+        let stop_hash = Hash256::decode(bytes)?;
+        let previous_filter_header = Hash256::decode(bytes)?;
+        let filter_hashes = decode_list(bytes, "cfheaders", MAX_GETCFHEADERS_SIZE)?;
+        Ok(CFHeaders {
+            filter_type,
+            stop_hash,
+            previous_filter_header,
+            filter_hashes,
+        })
+    }
+}
+
+/// Payload of `getcfcheckpt`.
+#[derive(Debug, Clone, Eq, PartialEq, Encode, Decode)]
+pub struct GetCFCheckpt {
+    pub filter_type: u8,
+    pub stop_hash: Hash256,
+}
+
+/// Payload of `cfcheckpt`, the filter headers at every [`CHECKPOINT_INTERVAL`] up to
+/// `stop_hash`.
+#[derive(Debug, Clone, Eq, PartialEq, Encode, Decode)]
+pub struct CFCheckpt {
+    pub filter_type: u8,
+    pub stop_hash: Hash256,
+    #[wire(var_int, max = MAX_PROTOCOL_MESSAGE_LENGTH as usize / std::mem::size_of::<Hash256>())]
+    pub filter_headers: Vec<Hash256>,
+}
+
+/// Filter header committing to `filter_hash` and, through `previous`, every filter before it.
+pub fn filter_header(filter_hash: &Hash256, previous: &Hash256) -> Hash256 {
+    let mut buffer = [0; 64];
+    buffer[..32].copy_from_slice(filter_hash.as_bytes());
+    buffer[32..].copy_from_slice(previous.as_bytes());
+    Hash256::hash(&buffer)
+}
+
+/// Checkpoints all `responses` agree on, and the height of the first one they don't.
+///
+/// BIP157 leaves resolving a conflict to the client: fetch `cfheaders` for the interval ending
+/// at that height from the disagreeing peers and compute the filters that differ from the
+/// blocks themselves.
+pub fn agree_checkpoints(responses: &[CFCheckpt]) -> Result<(Vec<Hash256>, Option<u32>)> {
+    let Some(first) = responses.first() else {
+        return Ok((Vec::new(), None));
+    };
+    if responses
+        .iter()
+        .any(|r| r.filter_type != first.filter_type || r.stop_hash != first.stop_hash)
+    {
+        return Err(Error::InvalidFilter(
+            "checkpoints for different stop hashes",
+        ));
+    }
+    let longest = responses
+        .iter()
+        .map(|r| r.filter_headers.len())
+        .max()
+        .unwrap_or_default();
+    let agreed = (0..longest)
+        .take_while(|i| {
+            responses
+                .iter()
+                .all(|r| r.filter_headers.get(*i) == first.filter_headers.get(*i))
+        })
+        .count();
+    let conflict = (agreed < longest).then(|| (agreed as u32 + 1) * CHECKPOINT_INTERVAL);
+    Ok((first.filter_headers[..agreed].to_vec(), conflict))
+}
+
+/// A serialized Golomb-coded set: the element count followed by the Golomb-Rice coded deltas
+/// of the sorted, hashed elements.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct BlockFilter {
+    pub content: Vec<u8>,
+}
+
+impl BlockFilter {
+    /// Basic filter of `block`: every output script except `OP_RETURN` ones, and the scripts
+    /// of the outputs its inputs spend, which the block itself doesn't contain.
+    pub fn basic<'a>(block: &'a Block, spent_scripts: impl IntoIterator<Item = &'a [u8]>) -> Self {
+        let created = block
+            .transactions
+            .iter()
+            .flat_map(|transaction| &transaction.outputs)
+            .map(|output| output.script_pubkey.as_slice())
+            .filter(|script| script.first() != Some(&OP_RETURN));
+        let elements: BTreeSet<&[u8]> = created
+            .chain(spent_scripts)
+            .filter(|script| !script.is_empty())
+            .collect();
+        Self::build(&block.hash(), elements)
+    }
+
+    fn build<'a>(block_hash: &Hash256, elements: impl IntoIterator<Item = &'a [u8]>) -> Self {
+        let elements: Vec<&[u8]> = elements.into_iter().collect();
+        let hasher = ElementHasher::new(block_hash, elements.len() as u64)
+            .expect("a block holds far fewer elements");
+        let mut values: Vec<u64> = elements.iter().map(|e| hasher.hash(e)).collect();
+        values.sort_unstable();
+
+        let mut content = BytesMut::new();
+        VariableInt(values.len() as u64).encode(&mut content);
+        let mut writer = BitWriter::new(content.to_vec());
+        let mut last = 0;
+        for value in values {
+            writer.golomb_rice(value - last, BASIC_P);
+            last = value;
+        }
+        BlockFilter {
+            content: writer.finish(),
+        }
+    }
+
+    /// Filter hash, what `cfheaders` carries.
+    pub fn hash(&self) -> Hash256 {
+        Hash256::hash(&self.content)
+    }
+
+    /// Whether any of `scripts` may be in the block, with about one false positive per 784931
+    /// queries.
+    pub fn match_any<'a>(
+        &self,
+        block_hash: &Hash256,
+        scripts: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<bool> {
+        let mut content = BytesMut::from(&self.content[..]);
+        let count = VariableInt::decode(&mut content)?.0;
+        // Every element takes at least the unary terminator and the remainder bits
+        if count > content.len() as u64 * 8 / (u64::from(BASIC_P) + 1) {
+            return Err(Error::InvalidFilter(
+                "element count exceeds the filter size",
+            ));
+        }
+        let hasher = ElementHasher::new(block_hash, count)?;
+        let mut queries: Vec<u64> = scripts.into_iter().map(|s| hasher.hash(s)).collect();
+        if count == 0 || queries.is_empty() {
+            return Ok(false);
+        }
+        queries.sort_unstable();
+
+        let mut reader = BitReader::new(&content);
+        let mut queries = queries.into_iter().peekable();
+        let mut value = 0_u64;
+        for _ in 0..count {
+            value = value
+                .checked_add(reader.golomb_rice(BASIC_P)?)
+                .ok_or(Error::InvalidFilter("filter value overflow"))?;
+            while let Some(query) = queries.next_if(|query| *query <= value) {
+                if query == value {
+                    return Ok(true);
+                }
+            }
+            if queries.peek().is_none() {
+                break;
+            }
+        }
+        Ok(false)
+    }
+}
+
+const OP_RETURN: u8 = 0x6a;
+
+/// Maps elements uniformly onto `[0, N * M)` with SipHash keyed by the block hash.
+struct ElementHasher {
+    k0: u64,
+    k1: u64,
+    range: u64,
+}
+
+impl ElementHasher {
+    fn new(block_hash: &Hash256, count: u64) -> Result<Self> {
+        let key = block_hash.as_bytes();
+        Ok(Self {
+            k0: u64::from_le_bytes(key[0..8].try_into().expect("8 bytes")),
+            k1: u64::from_le_bytes(key[8..16].try_into().expect("8 bytes")),
+            range: count
+                .checked_mul(BASIC_M)
+                .ok_or(Error::InvalidFilter("element count overflow"))?,
+        })
+    }
+
+    fn hash(&self, element: &[u8]) -> u64 {
+        let hash = SipHasher24::new_with_keys(self.k0, self.k1).hash(element);
+        ((u128::from(hash) * u128::from(self.range)) >> 64) as u64
+    }
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits used in the last byte, 8 when a new one is needed
+    used: u8,
+}
+
+impl BitWriter {
+    fn new(bytes: Vec<u8>) -> Self {
+        Self { bytes, used: 8 }
+    }
+
+    fn bit(&mut self, bit: bool) {
+        if self.used == 8 {
+            self.bytes.push(0);
+            self.used = 0;
+        }
+        if bit {
+            *self.bytes.last_mut().expect("just pushed") |= 0x80 >> self.used;
+        }
+        self.used += 1;
+    }
+
+    /// Quotient in unary, then the remainder in `p` bits, most significant first.
+    fn golomb_rice(&mut self, value: u64, p: u8) {
+        for _ in 0..value >> p {
+            self.bit(true);
+        }
+        self.bit(false);
+        for i in (0..p).rev() {
+            self.bit(value >> i & 1 == 1);
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn bit(&mut self) -> Result<bool> {
+        let byte = self
+            .bytes
+            .get(self.position / 8)
+            .ok_or(Error::NotEnoughBytes("filter"))?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Ok(bit)
+    }
+
+    fn golomb_rice(&mut self, p: u8) -> Result<u64> {
+        let mut quotient = 0_u64;
+        while self.bit()? {
+            quotient += 1;
+        }
+        let mut remainder = 0;
+        for _ in 0..p {
+            remainder = remainder << 1 | u64::from(self.bit()?);
+        }
+        quotient
+            .checked_shl(p.into())
+            .filter(|q| q >> p == quotient)
+            .map(|q| q | remainder)
+            .ok_or(Error::InvalidFilter("golomb-rice quotient overflow"))
+    }
+}
+
+/// Filter headers of the best chain, verified against agreed checkpoints.
+#[derive(Debug, Clone)]
+pub struct FilterHeaders {
+    filter_type: u8,
+    checkpoints: Vec<Hash256>,
+    /// Filter hash and header by height
+    entries: Vec<(Hash256, Hash256)>,
+}
+
+impl FilterHeaders {
+    /// Starts from genesis, `checkpoints` usually comes from [`agree_checkpoints`].
+    pub fn new(filter_type: u8, checkpoints: Vec<Hash256>) -> Self {
+        Self {
+            filter_type,
+            checkpoints,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Height of the last verified filter header, `None` before the first `cfheaders`.
+    pub fn height(&self) -> Option<u32> {
+        self.entries
+            .len()
+            .checked_sub(1)
+            .map(|height| height as u32)
+    }
+
+    pub fn header(&self, height: u32) -> Option<Hash256> {
+        self.entries.get(height as usize).map(|(_, header)| *header)
+    }
+
+    /// Request continuing after the last verified header, up to `stop_hash`.
+    pub fn next_request(&self, stop_hash: Hash256) -> FilterRequest {
+        FilterRequest {
+            filter_type: self.filter_type,
+            start_height: self.entries.len() as u32,
+            stop_hash,
+        }
+    }
+
+    /// Adds the headers from a `cfheaders`, whose range `chain` tells from the stop hash.
+    ///
+    /// The range has to connect to what we have, and every header landing on a checkpoint
+    /// height must match it. Headers past the start of the range are replaced, which is what
+    /// a block reorg looks like here. Returns the new height.
+    pub fn connect(&mut self, chain: &HeaderChain, cfheaders: &CFHeaders) -> Result<u32> {
+        if cfheaders.filter_type != self.filter_type {
+            return Err(Error::InvalidFilter("unexpected filter type"));
+        }
+        let stop = chain
+            .get(&cfheaders.stop_hash)
+            .filter(|entry| chain.is_active(entry))
+            .ok_or(Error::InvalidFilter(
+                "cfheaders stop hash not in the best chain",
+            ))?;
+        let count = cfheaders.filter_hashes.len() as u32;
+        if count == 0 || count > stop.height + 1 {
+            return Err(Error::InvalidFilter(
+                "cfheaders range does not fit the chain",
+            ));
+        }
+        let start = stop.height + 1 - count;
+        if start as usize > self.entries.len() {
+            return Err(Error::InvalidFilter("cfheaders do not connect"));
+        }
+        let previous = match start {
+            0 => Hash256::ZERO,
+            _ => self.entries[start as usize - 1].1,
+        };
+        if cfheaders.previous_filter_header != previous {
+            return Err(Error::FilterHeaderMismatch {
+                height: start.saturating_sub(1),
+                expected: previous,
+                actual: cfheaders.previous_filter_header,
+            });
+        }
+
+        let headers = cfheaders.filter_headers();
+        for (height, header) in (start..).zip(&headers) {
+            if height == 0 || !height.is_multiple_of(CHECKPOINT_INTERVAL) {
+                continue;
+            }
+            let index = (height / CHECKPOINT_INTERVAL - 1) as usize;
+            if let Some(checkpoint) = self.checkpoints.get(index) {
+                if checkpoint != header {
+                    return Err(Error::FilterHeaderMismatch {
+                        height,
+                        expected: *checkpoint,
+                        actual: *header,
+                    });
+                }
+            }
+        }
+
+        self.entries.truncate(start as usize);
+        self.entries
+            .extend(cfheaders.filter_hashes.iter().copied().zip(headers));
+        Ok(stop.height)
+    }
+
+    /// Checks a `cfilter` hashes to the filter hash we verified for its block.
+    pub fn check_filter(&self, chain: &HeaderChain, cfilter: &CFilter) -> Result<()> {
+        if cfilter.filter_type != self.filter_type {
+            return Err(Error::InvalidFilter("unexpected filter type"));
+        }
+        let entry = chain
+            .get(&cfilter.block_hash)
+            .filter(|entry| chain.is_active(entry))
+            .ok_or(Error::InvalidFilter("cfilter block not in the best chain"))?;
+        let (expected, _) = self
+            .entries
+            .get(entry.height as usize)
+            .ok_or(Error::InvalidFilter(
+                "cfilter above the verified filter headers",
+            ))?;
+        let actual = cfilter.filter.hash();
+        if *expected != actual {
+            return Err(Error::FilterHeaderMismatch {
+                height: entry.height,
+                expected: *expected,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::bitcoin::network::Network;
+
+    const GENESIS_BLOCK: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+    fn genesis(network: Network) -> Block {
+        let mut bytes = BytesMut::from(&hex::decode(GENESIS_BLOCK).unwrap()[..]);
+        let mut block = Block::decode(&mut bytes).unwrap();
+        block.header = network.genesis_header();
+        block
+    }
+
+    #[test]
+    fn bip158_genesis_vectors() {
+        let block = genesis(Network::Testnet3);
+        let filter = BlockFilter::basic(&block, []);
+        assert_eq!(hex::encode(&filter.content), "019dfca8");
+        assert_eq!(
+            filter_header(&filter.hash(), &Hash256::ZERO).to_string(),
+            "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750"
+        );
+
+        let script = &block.transactions[0].outputs[0].script_pubkey;
+        assert!(filter.match_any(&block.hash(), [&script[..]]).unwrap());
+        assert!(!filter.match_any(&block.hash(), [&[0x51][..]]).unwrap());
+        assert!(!filter.match_any(&block.hash(), []).unwrap());
+    }
+
+    #[test]
+    fn oversized_element_count() {
+        let block_hash = genesis(Network::Testnet3).hash();
+        for count in [u64::MAX / 2, 3] {
+            let mut content = BytesMut::new();
+            VariableInt(count).encode(&mut content);
+            content.extend_from_slice(&[0xff; 4]);
+            let filter = BlockFilter {
+                content: content.to_vec(),
+            };
+            assert!(matches!(
+                filter.match_any(&block_hash, [&[0x51][..]]),
+                Err(Error::InvalidFilter(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn match_many_scripts() {
+        let scripts: Vec<Vec<u8>> = (0..500_u32).map(|i| i.to_le_bytes().to_vec()).collect();
+        let block_hash = Network::Mainnet.genesis_hash();
+        let filter = BlockFilter::build(&block_hash, scripts.iter().map(Vec::as_slice));
+
+        for script in scripts.iter().step_by(37) {
+            assert!(filter.match_any(&block_hash, [&script[..]]).unwrap());
+        }
+        let absent: Vec<Vec<u8>> = (1000..1100_u32).map(|i| i.to_le_bytes().to_vec()).collect();
+        assert!(!filter
+            .match_any(&block_hash, absent.iter().map(Vec::as_slice))
+            .unwrap());
+        let mut mixed = absent.clone();
+        mixed.push(scripts[499].clone());
+        assert!(filter
+            .match_any(&block_hash, mixed.iter().map(Vec::as_slice))
+            .unwrap());
+    }
+
+    #[test]
+    fn checkpoint_agreement() {
+        let hashes: Vec<Hash256> = (0..3_u8).map(|i| Hash256([i; 32])).collect();
+        let response = |filter_headers: &[Hash256]| CFCheckpt {
+            filter_type: BASIC_FILTER,
+            stop_hash: Hash256([9; 32]),
+            filter_headers: filter_headers.to_vec(),
+        };
+
+        let agreed = agree_checkpoints(&[response(&hashes), response(&hashes)]).unwrap();
+        assert_eq!(agreed, (hashes.clone(), None));
+
+        let mut lying = hashes.clone();
+        lying[1] = Hash256([7; 32]);
+        let agreed = agree_checkpoints(&[response(&hashes), response(&lying)]).unwrap();
+        assert_eq!(agreed, (hashes[..1].to_vec(), Some(2000)));
+
+        let agreed = agree_checkpoints(&[response(&hashes), response(&hashes[..2])]).unwrap();
+        assert_eq!(agreed, (hashes[..2].to_vec(), Some(3000)));
+    }
+
+    #[test]
+    fn connect_and_check_filters() {
+        use crate::p2p::bitcoin::chain::tests::extend;
+
+        let network = Network::Regtest;
+        let mut chain = HeaderChain::new(network);
+        let headers = extend(&network.genesis_header(), 1001, 0);
+        let now = headers.last().unwrap().time;
+        for header in &headers {
+            chain.connect(*header, now).unwrap();
+        }
+        let hashes: Vec<Hash256> = std::iter::once(network.genesis_hash())
+            .chain(headers.iter().map(|header| header.hash()))
+            .collect();
+        let filters: Vec<BlockFilter> = hashes
+            .iter()
+            .map(|hash| BlockFilter::build(hash, [&hash.as_bytes()[..]]))
+            .collect();
+        let filter_hashes: Vec<Hash256> = filters.iter().map(BlockFilter::hash).collect();
+        let cfheaders = |start: usize, end: usize, previous: Hash256| CFHeaders {
+            filter_type: BASIC_FILTER,
+            stop_hash: hashes[end - 1],
+            previous_filter_header: previous,
+            filter_hashes: filter_hashes[start..end].to_vec(),
+        };
+        let all = cfheaders(0, 1002, Hash256::ZERO).filter_headers();
+
+        // A checkpoint that contradicts the served headers
+        let mut verified = FilterHeaders::new(BASIC_FILTER, vec![Hash256([1; 32])]);
+        assert!(matches!(
+            verified.connect(&chain, &cfheaders(0, 1002, Hash256::ZERO)),
+            Err(Error::FilterHeaderMismatch { height: 1000, .. })
+        ));
+
+        let mut verified = FilterHeaders::new(BASIC_FILTER, vec![all[1000]]);
+        assert_eq!(
+            verified
+                .connect(&chain, &cfheaders(0, 600, Hash256::ZERO))
+                .unwrap(),
+            599
+        );
+        assert_eq!(verified.next_request(hashes[1001]).start_height, 600);
+        assert!(matches!(
+            verified.connect(&chain, &cfheaders(600, 1002, Hash256([2; 32]))),
+            Err(Error::FilterHeaderMismatch { height: 599, .. })
+        ));
+        assert_eq!(
+            verified
+                .connect(&chain, &cfheaders(600, 1002, all[599]))
+                .unwrap(),
+            1001
+        );
+        assert_eq!(verified.header(1001), Some(all[1001]));
+
+        let cfilter = |height: usize, filter: &BlockFilter| CFilter {
+            filter_type: BASIC_FILTER,
+            block_hash: hashes[height],
+            filter: filter.clone(),
+        };
+        assert!(verified
+            .check_filter(&chain, &cfilter(5, &filters[5]))
+            .is_ok());
+        assert!(matches!(
+            verified.check_filter(&chain, &cfilter(5, &filters[6])),
+            Err(Error::FilterHeaderMismatch { height: 5, .. })
+        ));
+    }
+}
@@ -1,33 +1,271 @@
 use super::{
     codec::BitcoinCodec,
-    protocol::{Address, Command, Message, Payload, VersionMessage},
+    compact::{SendCmpct, CMPCT_VERSION_WTXID},
+    config::{unix_time, HandshakeConfig, SHORT_IDS_BLOCKS_VERSION, WTXID_RELAY_VERSION},
+    error::HandshakeError,
+    peer::PeerInfo,
+    protocol::{Command, Message, Payload, VersionMessage},
+    transport::{self, Role},
+    Error,
 };
-use anyhow::Result;
+use bytes::BytesMut;
 use futures::{SinkExt, StreamExt};
+use std::{future::Future, net::SocketAddr, time::Duration};
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::{TcpStream, ToSocketAddrs},
-    sync::mpsc::{Receiver, Sender},
+    sync::{
+        mpsc::{Receiver, Sender},
+        watch, OwnedSemaphorePermit,
+    },
+    time::{Instant, MissedTickBehavior},
+};
+use tokio_util::{
+    codec::{Framed, FramedParts},
+    sync::CancellationToken,
 };
-use tokio_util::codec::Framed;
 
 pub struct Handshake {
     stream_rx: Receiver<Message>,
     sink_tx: Sender<Message>,
+    latency_rx: watch::Receiver<Option<Duration>>,
+    info_rx: watch::Receiver<PeerInfo>,
+    session_id: Option<[u8; 32]>,
+}
+
+/// Progress of the version/verack exchange, from our side of the connection.
+///
+/// Either way the first thing we expect is the peer's version: outbound ours was sent before the
+/// state machine starts, inbound it answers the peer's. Our verack goes out as soon as the peer's
+/// version arrives, after which only the peer's verack is missing.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+enum State {
+    #[default]
+    AwaitingVersion,
+    AwaitingVerAck,
+    Ready,
+}
+
+impl State {
+    fn next(self, payload: &Payload) -> Result<Self, Error> {
+        match (self, payload) {
+            (Self::AwaitingVersion, Payload::Version(_)) => Ok(Self::AwaitingVerAck),
+            (Self::AwaitingVersion, _) => Err(Error::ProtocolViolation(
+                "expected version as the first message",
+            )),
+            (Self::AwaitingVerAck, Payload::Version(_)) => {
+                Err(Error::ProtocolViolation("duplicate version message"))
+            }
+            (Self::AwaitingVerAck, Payload::VerAck) => Ok(Self::Ready),
+            // Feature negotiation (wtxidrelay, sendaddrv2, ...) happens between version and verack
+            (Self::AwaitingVerAck, _) => Ok(Self::AwaitingVerAck),
+            (Self::Ready, Payload::Version(_)) => {
+                Err(Error::ProtocolViolation("duplicate version message"))
+            }
+            (Self::Ready, Payload::VerAck) => {
+                Err(Error::ProtocolViolation("duplicate verack message"))
+            }
+            (Self::Ready, Payload::SendAddrV2) => {
+                Err(Error::ProtocolViolation("sendaddrv2 after verack"))
+            }
+            (Self::Ready, Payload::WtxIdRelay) => {
+                Err(Error::ProtocolViolation("wtxidrelay after verack"))
+            }
+            (Self::Ready, _) => Ok(Self::Ready),
+        }
+    }
+}
+
+/// Reasons to drop a peer as soon as its version arrives.
+///
+/// The nonce registry holds the nonce of every version we sent on open connections, this one's
+/// included, so finding the peer's nonce there means we are talking to ourselves. Old clients
+/// send a zero nonce, which never counts.
+fn check_version(version: &VersionMessage, config: &HandshakeConfig) -> Result<(), HandshakeError> {
+    if version.nonce != 0 && config.nonce_registry.contains(version.nonce) {
+        return Err(HandshakeError::SelfConnection);
+    }
+    if version.version < config.min_peer_version {
+        return Err(HandshakeError::VersionTooOld {
+            version: version.version,
+            minimum: config.min_peer_version,
+        });
+    }
+    if !version.services.contains(config.required_services) {
+        return Err(HandshakeError::MissingServices {
+            required: config.required_services,
+            offered: version.services,
+        });
+    }
+    Ok(())
+}
+
+/// Runs `future` until `deadline`, giving up early once `cancellation` fires.
+async fn bounded<T>(
+    cancellation: &CancellationToken,
+    deadline: Instant,
+    what: &'static str,
+    future: impl Future<Output = T>,
+) -> Result<T, HandshakeError> {
+    // Cancelling also stops the connection tasks, which must not read as a disconnect
+    tokio::select! {
+        biased;
+        _ = cancellation.cancelled() => Err(HandshakeError::Cancelled),
+        output = future => Ok(output),
+        _ = tokio::time::sleep_until(deadline) => Err(HandshakeError::Timeout(what)),
+    }
 }
 
 impl Handshake {
-    pub async fn connect(address: impl ToSocketAddrs) -> Result<Self> {
-        let stream = TcpStream::connect(address).await?;
+    pub async fn connect(address: impl ToSocketAddrs) -> Result<Self, HandshakeError> {
+        Self::connect_with(address, HandshakeConfig::default()).await
+    }
+
+    pub async fn connect_with(
+        address: impl ToSocketAddrs,
+        config: HandshakeConfig,
+    ) -> Result<Self, HandshakeError> {
+        let cancellation = config.cancellation.clone();
+        let connect_deadline = Instant::now() + config.connect_timeout;
+        let connect = TcpStream::connect(address);
+        let mut stream = bounded(&cancellation, connect_deadline, "connect", connect).await??;
+        let remote = stream.peer_addr()?;
+        let network = config.network;
+        let deadline = Instant::now() + config.handshake_timeout;
         tracing::debug!("Connection established");
 
-        let framed_stream = Framed::new(stream, BitcoinCodec);
+        let codec = BitcoinCodec::new(network).with_limits(config.limits.clone());
+        let mut session_id = None;
+        let framed_stream = if config.wants_v2() {
+            let v2 = transport::handshake(&mut stream, network, Role::Initiator, BytesMut::new());
+            match bounded(&cancellation, deadline, "handshake", v2).await? {
+                Ok((session, leftover)) => {
+                    tracing::debug!("v2 transport established");
+                    session_id = Some(session.session_id());
+                    let mut parts = FramedParts::new::<Message>(stream, codec.with_v2(session));
+                    parts.read_buf = leftover;
+                    Framed::from_parts(parts)
+                }
+                // A v1-only peer hangs up on the key exchange, try again in plaintext
+                Err(Error::IO(e)) => {
+                    tracing::warn!("v2 handshake failed, falling back to v1: {e}");
+                    let connect_deadline = Instant::now() + config.connect_timeout;
+                    let connect = TcpStream::connect(remote);
+                    let stream =
+                        bounded(&cancellation, connect_deadline, "connect", connect).await??;
+                    Framed::new(stream, codec)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            Framed::new(stream, codec)
+        };
+        Self::start(
+            framed_stream,
+            remote,
+            config,
+            true,
+            session_id,
+            None,
+            deadline,
+        )
+        .await
+    }
+
+    /// Runs the responder side of the handshake on an accepted connection: we wait for the
+    /// peer's version before sending ours.
+    ///
+    /// With [`HandshakeConfig::v2_transport`] set, peers opening with a BIP324 key exchange get
+    /// an encrypted session while v1 peers are still served in plaintext.
+    pub async fn accept(
+        stream: TcpStream,
+        config: HandshakeConfig,
+    ) -> Result<Self, HandshakeError> {
+        Self::accept_with_permit(stream, config, None).await
+    }
+
+    /// Like [`Handshake::accept`], `permit` is released once the connection closes.
+    pub(super) async fn accept_with_permit(
+        mut stream: TcpStream,
+        config: HandshakeConfig,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> Result<Self, HandshakeError> {
+        let remote = stream.peer_addr()?;
+        let network = config.network;
+        let cancellation = config.cancellation.clone();
+        let deadline = Instant::now() + config.handshake_timeout;
+        tracing::debug!("Connection accepted from {remote}");
+
+        let codec = BitcoinCodec::new(network).with_limits(config.limits.clone());
+        let mut session_id = None;
+        let parts = if config.v2_transport {
+            let sniff = transport::sniff_v1(&mut stream, network);
+            let (v1, received) = bounded(&cancellation, deadline, "handshake", sniff).await??;
+            if v1 {
+                let mut parts = FramedParts::new::<Message>(stream, codec);
+                parts.read_buf = received;
+                parts
+            } else {
+                let v2 = transport::handshake(&mut stream, network, Role::Responder, received);
+                let (session, leftover) =
+                    bounded(&cancellation, deadline, "handshake", v2).await??;
+                tracing::debug!("v2 transport established");
+                session_id = Some(session.session_id());
+                let mut parts = FramedParts::new::<Message>(stream, codec.with_v2(session));
+                parts.read_buf = leftover;
+                parts
+            }
+        } else {
+            FramedParts::new::<Message>(stream, codec)
+        };
+        Self::start(
+            Framed::from_parts(parts),
+            remote,
+            config,
+            false,
+            session_id,
+            permit,
+            deadline,
+        )
+        .await
+    }
+
+    /// Spawns the tasks driving the connection and waits until `deadline` for the handshake to
+    /// finish.
+    ///
+    /// The outbound side sends its version right away, the inbound side answers the peer's.
+    /// Both tasks stop, closing the connection, as soon as either of them does.
+    async fn start<S>(
+        framed_stream: Framed<S, BitcoinCodec>,
+        remote: SocketAddr,
+        config: HandshakeConfig,
+        outbound: bool,
+        session_id: Option<[u8; 32]>,
+        permit: Option<OwnedSemaphorePermit>,
+        deadline: Instant,
+    ) -> Result<Self, HandshakeError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let network = config.network;
+        let cancellation = config.cancellation.clone();
+        let shutdown = cancellation.child_token();
         let (mut sink, mut stream) = framed_stream.split();
         let (sink_tx, mut sink_rx) = tokio::sync::mpsc::channel(1);
         let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
         let (stream_tx, stream_rx) = tokio::sync::mpsc::channel(1);
+        let (latency_tx, latency_rx) = watch::channel(None);
 
+        let sink_shutdown = shutdown.clone();
         tokio::spawn(async move {
-            while let Some(message) = sink_rx.recv().await {
+            loop {
+                let message = tokio::select! {
+                    message = sink_rx.recv() => match message {
+                        Some(message) => message,
+                        None => break,
+                    },
+                    _ = sink_shutdown.cancelled() => break,
+                };
                 if let Err(e) = sink.send(message).await {
                     tracing::error!("Error: {}", e);
                     break;
@@ -36,87 +274,847 @@ impl Handshake {
         });
 
         let sink_tx_inner = sink_tx.clone();
+        let reader_shutdown = shutdown.clone();
         tokio::spawn(async move {
-            let version_message = VersionMessage {
-                version: 70016,
-                timestamp: 0,
-                user_agent: "/ramen/".into(),
-                addr_recv: Address {
-                    time: (),
-                    services: 0,
-                    ip: "::".parse().unwrap(),
-                    port: 0.into(),
-                },
-                addr_from: Address {
-                    time: (),
-                    services: 0,
-                    ip: "::".parse().unwrap(),
-                    port: 0.into(),
-                },
-                nonce: 0,
-                services: 0,
-                start_height: 0,
-                relay: false,
-            };
-            let message = Message::new(
-                0xD9B4BEF9,
-                Command::Version,
-                Payload::Version(version_message),
-            );
-            tracing::info!("Sending version message: {message:?}");
-            let _ = sink_tx_inner.send(message).await;
+            let _permit = permit;
+            let _shutdown = reader_shutdown.clone().drop_guard();
+            let nonce = config.nonce_registry.register(config.nonce);
+            let mut version_message = config.version_message(remote);
+            version_message.nonce = nonce.nonce();
+            let our_version = version_message.version;
+            let mut version_message = Some(version_message);
+            if outbound {
+                let version_message = version_message.take().map(Payload::Version).unwrap();
+                let message = Message::new(network, Command::Version, version_message);
+                tracing::info!("Sending version message: {message:?}");
+                let _ = sink_tx_inner.send(message).await;
+            }
 
-            while let Some(message) = stream.next().await {
+            let mut state = State::default();
+            let mut ready_tx = Some(ready_tx);
+            let mut info_tx: Option<watch::Sender<PeerInfo>> = None;
+            let mut peer_version = 0;
+            let mut wtxid_relay_sent = false;
+            let mut wtxid_relay_received = false;
+            let mut keepalive = config.keepalive.map(|period| {
+                let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                interval
+            });
+            let mut pending_ping: Option<(u64, Instant)> = None;
+            let mut last_received = Instant::now();
+            loop {
+                let ping_deadline = pending_ping.map(|(_, sent)| sent + config.ping_timeout);
+                let message = tokio::select! {
+                    message = stream.next() => match message {
+                        Some(message) => message,
+                        None => break,
+                    },
+                    _ = reader_shutdown.cancelled() => break,
+                    // The caller dropped its receiver, nobody is listening to this peer anymore
+                    _ = stream_tx.closed() => break,
+                    _ = tokio::time::sleep_until(last_received + config.idle_timeout),
+                        if state == State::Ready =>
+                    {
+                        tracing::warn!("Nothing received for {:?}, disconnecting", config.idle_timeout);
+                        break;
+                    }
+                    _ = async { tokio::time::sleep_until(ping_deadline.unwrap()).await },
+                        if ping_deadline.is_some() =>
+                    {
+                        tracing::warn!("Ping unanswered for {:?}, disconnecting", config.ping_timeout);
+                        break;
+                    }
+                    _ = async { keepalive.as_mut().unwrap().tick().await },
+                        if keepalive.is_some() && state == State::Ready =>
+                    {
+                        if pending_ping.is_some() {
+                            tracing::warn!("Previous ping still unanswered");
+                            continue;
+                        }
+                        let nonce = rand::random();
+                        pending_ping = Some((nonce, Instant::now()));
+                        let message = Message::new(network, Command::Ping, Payload::Ping(nonce));
+                        if sink_tx_inner.send(message).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                last_received = Instant::now();
                 let message = match message {
                     Ok(message) => message,
+                    // The codec gives up on the stream after an error, so the handshake can't go on
+                    Err(e) if state != State::Ready => {
+                        tracing::error!("Handshake failed: {}", e);
+                        if let Some(ready_tx) = ready_tx.take() {
+                            let _ = ready_tx.send(Err(e.into()));
+                        }
+                        return;
+                    }
                     Err(e) => {
                         tracing::error!("Error: {}", e);
                         continue;
                     }
                 };
 
+                let was_ready = state == State::Ready;
+                state = match state.next(message.payload()) {
+                    Ok(state) => state,
+                    Err(e) => {
+                        tracing::error!("Handshake failed: {}", e);
+                        if let Some(ready_tx) = ready_tx.take() {
+                            let _ = ready_tx.send(Err(e.into()));
+                        }
+                        return;
+                    }
+                };
+
+                // Keepalive traffic is answered here and never reaches the caller
+                match message.payload() {
+                    Payload::Ping(nonce) => {
+                        let message = Message::new(network, Command::Pong, Payload::Pong(*nonce));
+                        if sink_tx_inner.send(message).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    Payload::Pong(nonce) => {
+                        match pending_ping {
+                            Some((expected, sent)) if expected == *nonce => {
+                                let rtt = sent.elapsed();
+                                tracing::debug!("Pong received after {rtt:?}");
+                                let _ = latency_tx.send(Some(rtt));
+                                pending_ping = None;
+                            }
+                            _ => tracing::warn!("Unsolicited pong received: {nonce}"),
+                        }
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                if let Some(info_tx) = &info_tx {
+                    info_tx.send_if_modified(|info| info.features.update(message.payload()));
+                }
+
+                if was_ready {
+                    if let Err(e) = stream_tx.send(message).await {
+                        tracing::error!("Error: {}", e);
+                        break;
+                    }
+                    continue;
+                }
+
                 match message.payload() {
                     Payload::Version(version) => {
                         tracing::info!("Version message received: {:?}", version);
-                        let message = Message::new(0xD9B4BEF9, Command::VerAck, Payload::VerAck);
-                        tracing::info!("Sending verack: {:?}", message);
+                        if let Err(e) = check_version(version, &config) {
+                            tracing::warn!("Disconnecting: {}", e);
+                            if let Some(ready_tx) = ready_tx.take() {
+                                let _ = ready_tx.send(Err(e));
+                            }
+                            return;
+                        }
+                        peer_version = version.version;
+                        let info = PeerInfo::new(remote, version, unix_time());
+                        info_tx = Some(watch::Sender::new(info));
+                        let mut replies = Vec::new();
+                        if let Some(version_message) = version_message.take() {
+                            replies.push((Command::Version, Payload::Version(version_message)));
+                        }
+                        if version.version >= WTXID_RELAY_VERSION
+                            && our_version >= WTXID_RELAY_VERSION
+                        {
+                            replies.push((Command::WtxIdRelay, Payload::WtxIdRelay));
+                            wtxid_relay_sent = true;
+                        }
+                        // BIP155 allows sendaddrv2 for any version, but older peers may choke on it
+                        if version.version >= WTXID_RELAY_VERSION {
+                            replies.push((Command::SendAddrV2, Payload::SendAddrV2));
+                        }
+                        replies.push((Command::VerAck, Payload::VerAck));
+                        for (command, payload) in replies {
+                            let message = Message::new(network, command, payload);
+                            tracing::info!("Sending {}: {:?}", message.command(), message);
+                            if sink_tx_inner.send(message).await.is_err() {
+                                return;
+                            }
+                        }
                     }
                     Payload::VerAck => {
                         tracing::info!("Verack message received");
-                        let _ = ready_tx.send(());
-                        break;
+                        if let Some(announce) = config.compact_blocks {
+                            if peer_version >= SHORT_IDS_BLOCKS_VERSION {
+                                let sendcmpct = SendCmpct {
+                                    announce,
+                                    version: CMPCT_VERSION_WTXID,
+                                };
+                                let message = Message::new(
+                                    network,
+                                    Command::SendCmpct,
+                                    Payload::SendCmpct(sendcmpct),
+                                );
+                                tracing::info!("Sending sendcmpct: {:?}", message);
+                                if sink_tx_inner.send(message).await.is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                    Payload::WtxIdRelay => {
+                        tracing::info!("WtxIdRelay received");
+                        wtxid_relay_received = true;
                     }
-                    Payload::SendHeaders => {
-                        tracing::info!("SendHeaders received");
+                    _ => {
+                        tracing::info!("{} received", message.command());
                     }
-                    Payload::Empty => {
-                        tracing::info!("Empty payload received");
+                }
+
+                if state == State::Ready {
+                    if let (Some(ready_tx), Some(info_tx)) = (ready_tx.take(), &info_tx) {
+                        let wtxid_relay = wtxid_relay_sent && wtxid_relay_received;
+                        info_tx.send_modify(|info| info.features.wtxid_relay = wtxid_relay);
+                        let missing = info_tx.borrow().features.missing(&config.required_features);
+                        if let Some(feature) = missing {
+                            let e = HandshakeError::MissingFeature(feature);
+                            tracing::warn!("Disconnecting: {}", e);
+                            let _ = ready_tx.send(Err(e));
+                            return;
+                        }
+                        let _ = ready_tx.send(Ok(info_tx.subscribe()));
                     }
                 }
             }
+        });
+
+        let info_rx = match bounded(&cancellation, deadline, "handshake", ready_rx).await {
+            Ok(Ok(ready)) => ready?,
+            Ok(Err(_)) => return Err(HandshakeError::PeerDisconnected),
+            Err(e) => {
+                shutdown.cancel();
+                return Err(e);
+            }
+        };
 
-            while let Some(message) = stream.next().await {
-                match message {
-                    Ok(message) => {
-                        if let Err(e) = stream_tx.send(message).await {
-                            tracing::error!("Error: {}", e);
+        Ok(Self {
+            stream_rx,
+            sink_tx,
+            latency_rx,
+            info_rx,
+            session_id,
+        })
+    }
+
+    /// BIP324 session id when the connection uses the v2 transport, `None` for plaintext v1.
+    ///
+    /// Both sides derive the same value, comparing it out of band rules out a man in the middle.
+    pub fn session_id(&self) -> Option<[u8; 32]> {
+        self.session_id
+    }
+
+    /// Whether both sides sent wtxidrelay, in which case transactions are announced by wtxid
+    /// (see [`Transaction::inv`](super::transaction::Transaction::inv)).
+    pub fn wtxid_relay(&self) -> bool {
+        self.info_rx.borrow().features.wtxid_relay
+    }
+
+    /// The peer's version details and negotiated features, as of now.
+    pub fn peer_info(&self) -> PeerInfo {
+        self.info_rx.borrow().clone()
+    }
+
+    /// Round-trip time of the most recent ping, updated by the keepalive task.
+    ///
+    /// Stays `None` unless [`HandshakeConfig::keepalive`] is set. Grab it before calling
+    /// [`Handshake::split`], the receiver keeps working afterwards.
+    pub fn latency(&self) -> watch::Receiver<Option<Duration>> {
+        self.latency_rx.clone()
+    }
+
+    /// Hands out the message channels along with the peer info, which keeps tracking features
+    /// the peer announces later on.
+    pub fn split(
+        self,
+    ) -> (
+        Sender<Message>,
+        Receiver<Message>,
+        watch::Receiver<PeerInfo>,
+    ) {
+        (self.sink_tx, self.stream_rx, self.info_rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::bitcoin::protocol::{Address, VersionMessage};
+
+    fn run(payloads: &[Payload]) -> Result<State, Error> {
+        payloads
+            .iter()
+            .try_fold(State::default(), |state, payload| state.next(payload))
+    }
+
+    fn version() -> Payload {
+        Payload::Version(VersionMessage {
+            version: 70016,
+            services: 0.into(),
+            timestamp: 0,
+            addr_recv: Address {
+                time: (),
+                services: 0.into(),
+                ip: "::".parse().unwrap(),
+                port: 0.into(),
+            },
+            addr_from: Address {
+                time: (),
+                services: 0.into(),
+                ip: "::".parse().unwrap(),
+                port: 0.into(),
+            },
+            nonce: 0,
+            user_agent: "/test/".into(),
+            start_height: 0,
+            relay: Some(false),
+        })
+    }
+
+    #[test]
+    fn version_then_verack() {
+        let state = run(&[version(), Payload::Empty, Payload::VerAck]).unwrap();
+        assert_eq!(state, State::Ready);
+    }
+
+    #[test]
+    fn verack_before_version() {
+        assert!(matches!(
+            run(&[Payload::VerAck]),
+            Err(Error::ProtocolViolation(_))
+        ));
+    }
+
+    #[test]
+    fn sendaddrv2_after_verack() {
+        assert!(run(&[version(), Payload::SendAddrV2, Payload::VerAck]).is_ok());
+        assert!(matches!(
+            run(&[version(), Payload::VerAck, Payload::SendAddrV2]),
+            Err(Error::ProtocolViolation(_))
+        ));
+    }
+
+    #[test]
+    fn duplicate_messages() {
+        assert!(matches!(
+            run(&[version(), version()]),
+            Err(Error::ProtocolViolation(_))
+        ));
+        assert!(matches!(
+            run(&[version(), Payload::VerAck, Payload::VerAck]),
+            Err(Error::ProtocolViolation(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn keepalive_against_local_peer() {
+        use crate::p2p::bitcoin::{HandshakeConfig, Network};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut peer = Framed::new(socket, BitcoinCodec::new(Network::Regtest));
+            let message = peer.next().await.unwrap().unwrap();
+            assert!(matches!(message.payload(), Payload::Version(_)));
+            for (command, payload) in [
+                (Command::Version, version()),
+                (Command::WtxIdRelay, Payload::WtxIdRelay),
+                (Command::VerAck, Payload::VerAck),
+                (Command::Ping, Payload::Ping(7)),
+            ] {
+                let message = Message::new(Network::Regtest, command, payload);
+                peer.send(message).await.unwrap();
+            }
+            let mut replies = Vec::new();
+            while replies.len() < 5 {
+                replies.push(peer.next().await.unwrap().unwrap().payload().clone());
+            }
+            let Some(Payload::Ping(nonce)) = replies.pop() else {
+                panic!("expected a keepalive ping, got {replies:?}");
+            };
+            assert_eq!(
+                replies,
+                [
+                    Payload::WtxIdRelay,
+                    Payload::SendAddrV2,
+                    Payload::VerAck,
+                    Payload::Pong(7)
+                ]
+            );
+            let message = Message::new(Network::Regtest, Command::Pong, Payload::Pong(nonce));
+            peer.send(message).await.unwrap();
+            peer
+        });
+
+        let config = HandshakeConfig::new()
+            .network(Network::Regtest)
+            .keepalive(Duration::from_millis(10));
+        let handshake = Handshake::connect_with(address, config).await.unwrap();
+        assert!(handshake.wtxid_relay());
+        let mut latency = handshake.latency();
+        latency.changed().await.unwrap();
+        assert!(latency.borrow().is_some());
+        drop(peer.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn unanswered_ping_disconnects() {
+        use crate::p2p::bitcoin::{HandshakeConfig, Network};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut peer = Framed::new(socket, BitcoinCodec::new(Network::Regtest));
+            serve_v1(&mut peer).await;
+            // Busy enough to never look idle, but our pings go unanswered
+            let mut chatter = tokio::time::interval(Duration::from_millis(5));
+            loop {
+                tokio::select! {
+                    // Closing with our pings unread may come out as a reset
+                    message = peer.next() => match message {
+                        Some(Ok(_)) => {}
+                        _ => break,
+                    },
+                    _ = chatter.tick() => {
+                        let message = Message::new(Network::Regtest, Command::Ping, Payload::Ping(7));
+                        if peer.send(message).await.is_err() {
                             break;
                         }
                     }
-                    Err(e) => {
-                        tracing::error!("Error: {}", e);
-                        continue;
-                    }
                 }
             }
         });
 
-        ready_rx.await?;
+        let config = HandshakeConfig::new()
+            .network(Network::Regtest)
+            .idle_timeout(Duration::from_millis(50))
+            .keepalive(Duration::from_millis(10))
+            .ping_timeout(Duration::from_millis(100));
+        let (_tx, mut rx, _info) = Handshake::connect_with(address, config)
+            .await
+            .unwrap()
+            .split();
+        assert!(rx.recv().await.is_none());
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sendcmpct_after_verack() {
+        use crate::p2p::bitcoin::{HandshakeConfig, Network};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut peer = Framed::new(socket, BitcoinCodec::new(Network::Regtest));
+            peer.next().await.unwrap().unwrap();
+            for (command, payload) in [
+                (Command::Version, version()),
+                (Command::VerAck, Payload::VerAck),
+            ] {
+                let message = Message::new(Network::Regtest, command, payload);
+                peer.send(message).await.unwrap();
+            }
+            let mut replies = Vec::new();
+            while replies.len() < 4 {
+                replies.push(peer.next().await.unwrap().unwrap().payload().clone());
+            }
+            assert_eq!(
+                replies.pop(),
+                Some(Payload::SendCmpct(SendCmpct {
+                    announce: true,
+                    version: CMPCT_VERSION_WTXID,
+                }))
+            );
+            peer
+        });
+
+        let config = HandshakeConfig::new()
+            .network(Network::Regtest)
+            .compact_blocks(true);
+        let _handshake = Handshake::connect_with(address, config).await.unwrap();
+        drop(peer.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn peer_info_tracks_features() {
+        use crate::p2p::bitcoin::{HandshakeConfig, Network};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut peer = Framed::new(socket, BitcoinCodec::new(Network::Regtest));
+            peer.next().await.unwrap().unwrap();
+            let sendcmpct = SendCmpct {
+                announce: false,
+                version: CMPCT_VERSION_WTXID,
+            };
+            for (command, payload) in [
+                (Command::Version, version()),
+                (Command::SendAddrV2, Payload::SendAddrV2),
+                (Command::VerAck, Payload::VerAck),
+                (Command::SendHeaders, Payload::SendHeaders),
+                (Command::SendCmpct, Payload::SendCmpct(sendcmpct)),
+            ] {
+                let message = Message::new(Network::Regtest, command, payload);
+                peer.send(message).await.unwrap();
+            }
+            peer
+        });
+
+        let config = HandshakeConfig::new().network(Network::Regtest);
+        let handshake = Handshake::connect_with(address, config).await.unwrap();
+        let info = handshake.peer_info();
+        assert_eq!(info.address, address);
+        assert_eq!(info.version, 70016);
+        assert_eq!(info.user_agent, "/test/");
+        assert!(info.features.addr_v2 && !info.features.wtxid_relay);
+
+        let (_tx, mut rx, info) = handshake.split();
+        assert_eq!(rx.recv().await.unwrap().payload(), &Payload::SendHeaders);
+        assert!(matches!(
+            rx.recv().await.unwrap().payload(),
+            Payload::SendCmpct(_)
+        ));
+        let features = info.borrow().features.clone();
+        assert!(features.send_headers);
+        assert_eq!(features.compact_blocks.map(|c| c.announce), Some(false));
+        drop(peer.await.unwrap());
+    }
+
+    async fn serve_v1(peer: &mut Framed<tokio::net::TcpStream, BitcoinCodec>) {
+        use crate::p2p::bitcoin::Network;
+
+        peer.next().await.unwrap().unwrap();
+        for (command, payload) in [
+            (Command::Version, version()),
+            (Command::VerAck, Payload::VerAck),
+        ] {
+            let message = Message::new(Network::Regtest, command, payload);
+            peer.send(message).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn v2_against_local_peer() {
+        use crate::p2p::bitcoin::{HandshakeConfig, Network, NODE_P2P_V2};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let (v1, received) = transport::sniff_v1(&mut socket, Network::Regtest)
+                .await
+                .unwrap();
+            assert!(!v1);
+            let (session, leftover) =
+                transport::handshake(&mut socket, Network::Regtest, Role::Responder, received)
+                    .await
+                    .unwrap();
+            let session_id = session.session_id();
+            let codec = BitcoinCodec::new(Network::Regtest).with_v2(session);
+            let mut parts = FramedParts::new::<Message>(socket, codec);
+            parts.read_buf = leftover;
+            let mut peer = Framed::from_parts(parts);
+            serve_v1(&mut peer).await;
+            (peer, session_id)
+        });
+
+        let config = HandshakeConfig::new()
+            .network(Network::Regtest)
+            .v2_transport(true)
+            .peer_services(NODE_P2P_V2);
+        let handshake = Handshake::connect_with(address, config).await.unwrap();
+        let (_peer, session_id) = peer.await.unwrap();
+        assert_eq!(handshake.session_id(), Some(session_id));
+    }
+
+    #[tokio::test]
+    async fn v2_falls_back_to_v1() {
+        use crate::p2p::bitcoin::{HandshakeConfig, Network, NODE_P2P_V2};
+        use tokio::{io::AsyncReadExt, net::TcpListener};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            // Like a v1-only node, drop the connection on a bad magic
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut header = [0; 24];
+            socket.read_exact(&mut header).await.unwrap();
+            drop(socket);
+
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut peer = Framed::new(socket, BitcoinCodec::new(Network::Regtest));
+            serve_v1(&mut peer).await;
+            peer
+        });
+
+        let config = HandshakeConfig::new()
+            .network(Network::Regtest)
+            .v2_transport(true)
+            .peer_services(NODE_P2P_V2);
+        let handshake = Handshake::connect_with(address, config).await.unwrap();
+        assert_eq!(handshake.session_id(), None);
+        drop(peer.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn missing_required_services() {
+        use crate::p2p::bitcoin::{HandshakeConfig, Network, NODE_NETWORK, NODE_WITNESS};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut peer = Framed::new(socket, BitcoinCodec::new(Network::Regtest));
+            serve_v1(&mut peer).await;
+            // Dropped without a reply to our version
+            assert!(peer.next().await.is_none());
+        });
+
+        let config = HandshakeConfig::new()
+            .network(Network::Regtest)
+            .required_services(NODE_NETWORK | NODE_WITNESS);
+        let error = Handshake::connect_with(address, config)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, HandshakeError::MissingServices { .. }));
+        peer.await.unwrap();
+    }
+
+    #[test]
+    fn version_checks() {
+        use crate::p2p::bitcoin::NODE_NETWORK;
+
+        let config = HandshakeConfig::new();
+        let Payload::Version(mut version) = version() else {
+            unreachable!()
+        };
+        version.nonce = 7;
+        assert!(check_version(&version, &config).is_ok());
+        let _nonce = config.nonce_registry.register(Some(7));
+        assert!(matches!(
+            check_version(&version, &config),
+            Err(HandshakeError::SelfConnection)
+        ));
+
+        let _zero = config.nonce_registry.register(Some(0));
+        version.nonce = 0;
+        assert!(check_version(&version, &config).is_ok());
+
+        version.nonce = 8;
+        assert!(matches!(
+            check_version(&version, &config.clone().required_services(NODE_NETWORK)),
+            Err(HandshakeError::MissingServices { .. })
+        ));
+        version.version = 31799;
+        assert!(matches!(
+            check_version(&version, &config),
+            Err(HandshakeError::VersionTooOld { version: 31799, .. })
+        ));
+        assert!(check_version(&version, &config.min_peer_version(209)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn timeouts_and_cancellation() {
+        use crate::p2p::bitcoin::{HandshakeConfig, Network};
+        use tokio::net::TcpListener;
+
+        // Reads our version and never answers, then hangs up on the third connection
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let mut peers = Vec::new();
+            for _ in 0..3 {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut peer = Framed::new(socket, BitcoinCodec::new(Network::Regtest));
+                peer.next().await.unwrap().unwrap();
+                peers.push(peer);
+            }
+        });
+
+        let config = HandshakeConfig::new()
+            .network(Network::Regtest)
+            .handshake_timeout(Duration::from_millis(50));
+        let error = Handshake::connect_with(address, config.clone())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, HandshakeError::Timeout("handshake")));
+
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            cancel.cancel();
+        });
+        let config = config
+            .handshake_timeout(Duration::from_secs(60))
+            .cancellation(token);
+        let error = Handshake::connect_with(address, config.clone())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, HandshakeError::Cancelled));
+
+        let error =
+            Handshake::connect_with(address, HandshakeConfig::new().network(Network::Regtest));
+        let (error, _) = tokio::join!(error, peer);
+        assert!(matches!(
+            error.err(),
+            Some(HandshakeError::PeerDisconnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn idle_peer_is_dropped() {
+        use crate::p2p::bitcoin::{HandshakeConfig, Network};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut peer = Framed::new(socket, BitcoinCodec::new(Network::Regtest));
+            serve_v1(&mut peer).await;
+            // Our replies, then the connection closes once the idle timeout hits
+            while let Some(message) = peer.next().await {
+                message.unwrap();
+            }
+        });
+
+        let config = HandshakeConfig::new()
+            .network(Network::Regtest)
+            .idle_timeout(Duration::from_millis(50));
+        let (_tx, mut rx, _info) = Handshake::connect_with(address, config)
+            .await
+            .unwrap()
+            .split();
+        assert!(rx.recv().await.is_none());
+        peer.await.unwrap();
+    }
 
-        Ok(Self { stream_rx, sink_tx })
+    #[tokio::test]
+    async fn dropping_the_receiver_disconnects() {
+        use crate::p2p::bitcoin::{HandshakeConfig, Network};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut peer = Framed::new(socket, BitcoinCodec::new(Network::Regtest));
+            serve_v1(&mut peer).await;
+            while let Some(message) = peer.next().await {
+                message.unwrap();
+            }
+        });
+
+        let config = HandshakeConfig::new().network(Network::Regtest);
+        let (_tx, rx, _info) = Handshake::connect_with(address, config)
+            .await
+            .unwrap()
+            .split();
+        drop(rx);
+        peer.await.unwrap();
     }
 
-    pub fn split(self) -> (Sender<Message>, Receiver<Message>) {
-        (self.sink_tx, self.stream_rx)
+    #[tokio::test]
+    async fn detects_self_connection() {
+        use crate::p2p::bitcoin::{HandshakeConfig, Network};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = HandshakeConfig::new().network(Network::Regtest);
+        let inbound = tokio::spawn({
+            let config = config.clone();
+            async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                Handshake::accept(socket, config).await
+            }
+        });
+
+        let outbound = Handshake::connect_with(address, config).await;
+        assert!(matches!(
+            inbound.await.unwrap().err(),
+            Some(HandshakeError::SelfConnection)
+        ));
+        assert!(matches!(
+            outbound.err(),
+            Some(HandshakeError::PeerDisconnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn codec_error_is_a_protocol_violation() {
+        use crate::p2p::bitcoin::{Error, HandshakeConfig, Network};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            // Mainnet magic on a regtest connection
+            let mut peer = Framed::new(socket, BitcoinCodec::new(Network::Mainnet));
+            let message = Message::new(Network::Mainnet, Command::Version, version());
+            peer.send(message).await.unwrap();
+            peer
+        });
+
+        let config = HandshakeConfig::new().network(Network::Regtest);
+        let error = Handshake::connect_with(address, config).await.err();
+        assert!(matches!(
+            error,
+            Some(HandshakeError::ProtocolViolation(Error::BadMagic { .. }))
+        ));
+        drop(peer.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn missing_required_features() {
+        use crate::p2p::bitcoin::{Features, HandshakeConfig, Network};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut peer = Framed::new(socket, BitcoinCodec::new(Network::Regtest));
+            serve_v1(&mut peer).await;
+            peer
+        });
+
+        let config = HandshakeConfig::new()
+            .network(Network::Regtest)
+            .required_features(Features {
+                wtxid_relay: true,
+                ..Default::default()
+            });
+        let error = Handshake::connect_with(address, config).await.err();
+        assert!(matches!(
+            error,
+            Some(HandshakeError::MissingFeature("wtxidrelay"))
+        ));
+        drop(peer.await.unwrap());
     }
 }
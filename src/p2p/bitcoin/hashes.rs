@@ -1,4 +1,7 @@
-use bytes::BytesMut;
+use super::{Decode, Encode, Error, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use sha2::{Digest, Sha256};
+use std::{fmt, str::FromStr};
 
 pub trait Checksum {
     fn sha256(&self) -> u32;
@@ -6,19 +9,104 @@ pub trait Checksum {
 
 impl Checksum for BytesMut {
     fn sha256(&self) -> u32 {
-        use sha2::{Digest, Sha256};
-        let mut hasher = Sha256::new();
-        hasher.update(self);
-        let result = hasher.finalize();
-        let mut hasher = Sha256::new();
-        hasher.update(result);
-        let result = hasher.finalize();
+        let result = Hash256::hash(self);
         let mut bytes = [0; 4];
-        bytes.copy_from_slice(&result[0..4]);
+        bytes.copy_from_slice(&result.0[0..4]);
         u32::from_le_bytes(bytes)
     }
 }
 
+/// Double SHA256 digest, as used for block and transaction ids.
+///
+/// Bytes are kept in the order they hash to and appear on the wire, `Display` and `FromStr`
+/// use the reversed hex notation block explorers and RPCs show.
+#[derive(Clone, Copy, Default, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct Hash256(pub [u8; 32]);
+
+impl Hash256 {
+    pub const ZERO: Self = Self([0; 32]);
+
+    pub fn hash(data: &[u8]) -> Self {
+        let first = Sha256::digest(data);
+        Self(Sha256::digest(first).into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for Hash256 {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl fmt::Display for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut reversed = self.0;
+        reversed.reverse();
+        f.write_str(&hex::encode(reversed))
+    }
+}
+
+impl fmt::Debug for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Hash256({self})")
+    }
+}
+
+impl FromStr for Hash256 {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut bytes = <[u8; 32]>::try_from(&hex::decode(s)?[..])?;
+        bytes.reverse();
+        Ok(Self(bytes))
+    }
+}
+
+/// Merkle root over `hashes`, along with whether the tree was mutated.
+///
+/// A tree is mutated when two identical hashes are paired up at any level, which lets a different
+/// list of leaves produce the same root (CVE-2012-2459). An empty list hashes to zero.
+pub fn merkle_root(mut hashes: Vec<Hash256>) -> (Hash256, bool) {
+    let mut mutated = false;
+    if hashes.is_empty() {
+        return (Hash256::ZERO, mutated);
+    }
+    while hashes.len() > 1 {
+        // Only the pairs already there count, the copy padding an odd level is expected
+        mutated |= hashes.chunks_exact(2).any(|pair| pair[0] == pair[1]);
+        if hashes.len() % 2 == 1 {
+            hashes.push(hashes[hashes.len() - 1]);
+        }
+        hashes = hashes
+            .chunks(2)
+            .map(|pair| Hash256::hash(&[pair[0].0, pair[1].0].concat()))
+            .collect();
+    }
+    (hashes[0], mutated)
+}
+
+impl Encode for Hash256 {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        buffer.put_slice(&self.0);
+        self.0.len()
+    }
+}
+
+impl Decode for Hash256 {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        if bytes.remaining() < 32 {
+            return Err(Error::NotEnoughBytes("hash"));
+        }
+        let hash = bytes[..32].try_into()?;
+        bytes.advance(32);
+        Ok(Self(hash))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -29,4 +117,39 @@ mod tests {
         let data = BytesMut::from_iter(data.iter());
         assert_eq!(data.sha256(), 3799180429);
     }
+
+    #[test]
+    fn merkle_root_block_100000() {
+        let txids = [
+            "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+            "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+            "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+            "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+        ];
+        let hashes: Vec<Hash256> = txids.iter().map(|txid| txid.parse().unwrap()).collect();
+        let (root, mutated) = merkle_root(hashes.clone());
+        assert_eq!(
+            root.to_string(),
+            "f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766"
+        );
+        assert!(!mutated);
+
+        let (odd_root, _) = merkle_root(hashes[..3].to_vec());
+        let duplicated = [&hashes[..3], &hashes[2..3]].concat();
+        assert_eq!(merkle_root(duplicated), (odd_root, true));
+
+        // A duplicated pair on an odd level is a mutation too
+        let (a, b) = (hashes[0], hashes[1]);
+        assert!(merkle_root(vec![a, a, b]).1);
+        assert!(!merkle_root(hashes[..3].to_vec()).1);
+    }
+
+    #[test]
+    fn hash256_display_is_reversed() {
+        let hex = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+        let hash: Hash256 = hex.parse().unwrap();
+        assert_eq!(hash.0[0], 0x6f);
+        assert_eq!(hash.0[31], 0x00);
+        assert_eq!(hash.to_string(), hex);
+    }
 }
@@ -0,0 +1,103 @@
+//! Inventory vectors used by `inv`, `getdata` and `notfound`.
+
+use super::{hashes::Hash256, Decode, Encode, Result};
+use bytes::BytesMut;
+
+/// Bitcoin Core's `MAX_INV_SZ`, the most entries a single inventory message may carry.
+pub const MAX_INV_SZ: usize = 50_000;
+
+const MSG_WITNESS_FLAG: u32 = 1 << 30;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum InvType {
+    Error,
+    Tx,
+    Block,
+    FilteredBlock,
+    CmpctBlock,
+    /// BIP339 announcement by wtxid
+    Wtx,
+    WitnessTx,
+    WitnessBlock,
+    WitnessFilteredBlock,
+    Unknown(u32),
+}
+
+impl From<u32> for InvType {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => Self::Error,
+            1 => Self::Tx,
+            2 => Self::Block,
+            3 => Self::FilteredBlock,
+            4 => Self::CmpctBlock,
+            5 => Self::Wtx,
+            x if x == MSG_WITNESS_FLAG | 1 => Self::WitnessTx,
+            x if x == MSG_WITNESS_FLAG | 2 => Self::WitnessBlock,
+            x if x == MSG_WITNESS_FLAG | 3 => Self::WitnessFilteredBlock,
+            x => Self::Unknown(x),
+        }
+    }
+}
+
+impl From<InvType> for u32 {
+    fn from(value: InvType) -> Self {
+        match value {
+            InvType::Error => 0,
+            InvType::Tx => 1,
+            InvType::Block => 2,
+            InvType::FilteredBlock => 3,
+            InvType::CmpctBlock => 4,
+            InvType::Wtx => 5,
+            InvType::WitnessTx => MSG_WITNESS_FLAG | 1,
+            InvType::WitnessBlock => MSG_WITNESS_FLAG | 2,
+            InvType::WitnessFilteredBlock => MSG_WITNESS_FLAG | 3,
+            InvType::Unknown(x) => x,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct InvVector {
+    pub inv_type: InvType,
+    pub hash: Hash256,
+}
+
+impl Encode for InvVector {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        u32::from(self.inv_type).encode(buffer) + self.hash.encode(buffer)
+    }
+}
+
+impl Decode for InvVector {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        let inv_type = u32::decode(bytes)?.into();
+        let hash = Hash256::decode(bytes)?;
+        Ok(InvVector { inv_type, hash })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn witness_types() {
+        assert_eq!(u32::from(InvType::WitnessTx), 0x40000001);
+        assert_eq!(u32::from(InvType::WitnessBlock), 0x40000002);
+        assert_eq!(InvType::from(0x40000002), InvType::WitnessBlock);
+        assert_eq!(InvType::from(0x40000005), InvType::Unknown(0x40000005));
+    }
+
+    #[test]
+    fn roundtrip() {
+        let inv = InvVector {
+            inv_type: InvType::Wtx,
+            hash: Hash256([0xab; 32]),
+        };
+        let mut buffer = BytesMut::new();
+        assert_eq!(inv.encode(&mut buffer), 36);
+        assert_eq!(&buffer[..4], b"\x05\0\0\0");
+        assert_eq!(InvVector::decode(&mut buffer).unwrap(), inv);
+    }
+}
@@ -0,0 +1,141 @@
+use super::{config::HandshakeConfig, handshake::Handshake};
+use futures::Stream;
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, ToSocketAddrs},
+    sync::Semaphore,
+};
+
+/// Bitcoin Core's default `-maxconnections`.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 125;
+
+/// Pause after a failed `accept`, errors like running out of file descriptors tend to persist.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Accepts incoming peers and runs the responder side of the handshake on each of them.
+#[derive(Debug)]
+pub struct Listener {
+    listener: TcpListener,
+    config: HandshakeConfig,
+    max_connections: usize,
+}
+
+impl Listener {
+    pub async fn bind(address: impl ToSocketAddrs, config: HandshakeConfig) -> io::Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        Ok(Self {
+            listener,
+            config,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+        })
+    }
+
+    /// Caps the connections open at once, handshakes in progress included. Sockets accepted
+    /// beyond the cap are closed right away.
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Yields every peer that completes the handshake, along with its address.
+    ///
+    /// Handshakes run concurrently in the background so a slow peer holds up no one else,
+    /// failed ones are only logged. Dropping the stream stops accepting connections.
+    pub fn incoming(self) -> impl Stream<Item = (SocketAddr, Handshake)> {
+        let (peer_tx, peer_rx) = tokio::sync::mpsc::channel(1);
+        let limit = Arc::new(Semaphore::new(self.max_connections));
+
+        tokio::spawn(async move {
+            loop {
+                let (socket, remote) = tokio::select! {
+                    accepted = self.listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            tracing::error!("Error: {}", e);
+                            tokio::select! {
+                                _ = tokio::time::sleep(ACCEPT_ERROR_BACKOFF) => continue,
+                                _ = peer_tx.closed() => break,
+                            }
+                        }
+                    },
+                    _ = peer_tx.closed() => break,
+                };
+                let Ok(permit) = limit.clone().try_acquire_owned() else {
+                    tracing::warn!("Connection limit reached, dropping {remote}");
+                    continue;
+                };
+
+                let config = self.config.clone();
+                let peer_tx = peer_tx.clone();
+                tokio::spawn(async move {
+                    match Handshake::accept_with_permit(socket, config, Some(permit)).await {
+                        Ok(handshake) => {
+                            let _ = peer_tx.send((remote, handshake)).await;
+                        }
+                        Err(e) => tracing::warn!("Handshake with {remote} failed: {}", e),
+                    }
+                });
+            }
+        });
+
+        futures::stream::unfold(peer_rx, |mut peer_rx| async move {
+            let peer = peer_rx.recv().await?;
+            Some((peer, peer_rx))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::bitcoin::{Network, NODE_P2P_V2};
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn accepts_up_to_the_limit() {
+        // Separate configs, clones would share the nonce registry and look like a self connection
+        let config = || HandshakeConfig::new().network(Network::Regtest);
+        let listener = Listener::bind("127.0.0.1:0", config())
+            .await
+            .unwrap()
+            .max_connections(1);
+        let address = listener.local_addr().unwrap();
+        let mut peers = std::pin::pin!(listener.incoming());
+
+        let outbound = Handshake::connect_with(address, config()).await.unwrap();
+        let (remote, inbound) = peers.next().await.unwrap();
+        assert_eq!(inbound.session_id(), None);
+        assert!(outbound.wtxid_relay() && inbound.wtxid_relay());
+        assert_eq!(remote.ip(), address.ip());
+
+        assert!(Handshake::connect_with(address, config()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn accepts_v1_and_v2() {
+        let config = || {
+            HandshakeConfig::new()
+                .network(Network::Regtest)
+                .v2_transport(true)
+        };
+        let listener = Listener::bind("127.0.0.1:0", config()).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut peers = std::pin::pin!(listener.incoming());
+
+        let v1 = HandshakeConfig::new().network(Network::Regtest);
+        let outbound = Handshake::connect_with(address, v1).await.unwrap();
+        let (_, inbound) = peers.next().await.unwrap();
+        assert_eq!(outbound.session_id(), None);
+        assert_eq!(inbound.session_id(), None);
+
+        let v2 = config().peer_services(NODE_P2P_V2);
+        let outbound = Handshake::connect_with(address, v2).await.unwrap();
+        let (_, inbound) = peers.next().await.unwrap();
+        assert!(outbound.session_id().is_some());
+        assert_eq!(outbound.session_id(), inbound.session_id());
+    }
+}
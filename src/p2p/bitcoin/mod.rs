@@ -1,16 +1,49 @@
 //! # Bitcoin protocol handshake
 //! Implementation based on [Protocol documentation](https://en.bitcoin.it/wiki/Protocol_documentation) on Wikipedia.
 
-mod codec;
-mod decode;
-mod encode;
-mod error;
+pub mod addr;
+pub mod block;
+pub mod bloom;
+pub mod chain;
+pub mod codec;
+pub mod compact;
+mod config;
+pub mod decode;
+pub mod encode;
+pub mod error;
+pub mod filter;
 mod handshake;
-mod hashes;
-mod protocol;
-
-use decode::Decode;
-use encode::Encode;
-use error::{Error, Result};
+pub mod hashes;
+pub mod inventory;
+mod listener;
+mod network;
+mod nonce;
+mod peer;
+pub mod pow;
+pub mod protocol;
+mod services;
+mod sync;
+pub mod transaction;
+mod transport;
 
+pub use codec::{BitcoinCodec, Limits, MAX_PROTOCOL_MESSAGE_LENGTH};
+pub use config::*;
+pub use decode::Decode;
+pub use encode::Encode;
+pub use error::{Error, HandshakeError, Result};
 pub use handshake::*;
+pub use handshake_derive::{Decode, Encode};
+pub use listener::{Listener, DEFAULT_MAX_CONNECTIONS};
+pub use network::Network;
+pub use nonce::NonceRegistry;
+pub use peer::{Features, PeerInfo};
+pub use protocol::{Command, Message, Payload};
+pub use services::*;
+pub use sync::{HeaderSync, SyncEvent};
+pub use transport::V2Session;
+
+/// Paths used by the code `#[derive(Encode, Decode)]` generates.
+#[doc(hidden)]
+pub mod __private {
+    pub use bytes;
+}
@@ -0,0 +1,285 @@
+use super::{block::BlockHeader, hashes::Hash256, pow::U256, Error};
+use std::{fmt, str::FromStr};
+
+/// Bitcoin networks we know how to talk to.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet3,
+    Testnet4,
+    Signet,
+    Regtest,
+}
+
+impl Network {
+    /// Message start bytes, read as a little-endian `u32` the way they appear on the wire.
+    pub fn magic(&self) -> u32 {
+        match self {
+            Self::Mainnet => 0xD9B4BEF9,
+            Self::Testnet3 => 0x0709110B,
+            Self::Testnet4 => 0x283F161C,
+            // Only the default signet, custom signets derive their magic from the challenge
+            Self::Signet => 0x40CF030A,
+            Self::Regtest => 0xDAB5BFFA,
+        }
+    }
+
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Self::Mainnet => 8333,
+            Self::Testnet3 => 18333,
+            Self::Testnet4 => 48333,
+            Self::Signet => 38333,
+            Self::Regtest => 18444,
+        }
+    }
+
+    pub fn dns_seeds(&self) -> &'static [&'static str] {
+        match self {
+            Self::Mainnet => &[
+                "seed.bitcoin.sipa.be",
+                "dnsseed.bluematt.me",
+                "dnsseed.bitcoin.dashjr-list-of-p2p-nodes.us",
+                "seed.bitcoinstats.com",
+                "seed.bitcoin.jonasschnelli.ch",
+                "seed.btc.petertodd.net",
+                "seed.bitcoin.sprovoost.nl",
+                "dnsseed.emzy.de",
+                "seed.bitcoin.wiz.biz",
+                "seed.mainnet.achownodes.xyz",
+            ],
+            Self::Testnet3 => &[
+                "testnet-seed.bitcoin.jonasschnelli.ch",
+                "seed.tbtc.petertodd.net",
+                "seed.testnet.bitcoin.sprovoost.nl",
+                "testnet-seed.bluematt.me",
+                "seed.testnet.achownodes.xyz",
+            ],
+            Self::Testnet4 => &[
+                "seed.testnet4.bitcoin.sprovoost.nl",
+                "seed.testnet4.wiz.biz",
+            ],
+            Self::Signet => &[
+                "seed.signet.bitcoin.sprovoost.nl",
+                "seed.signet.achownodes.xyz",
+            ],
+            Self::Regtest => &[],
+        }
+    }
+
+    pub fn genesis_hash(&self) -> Hash256 {
+        let hash = match self {
+            Self::Mainnet => "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            Self::Testnet3 => "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
+            Self::Testnet4 => "00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043",
+            Self::Signet => "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6",
+            Self::Regtest => "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+        };
+        hash.parse().expect("genesis hashes are valid hex")
+    }
+
+    /// Header of the genesis block, the root every header chain starts from.
+    pub fn genesis_header(&self) -> BlockHeader {
+        let (time, bits, nonce) = match self {
+            Self::Mainnet => (1231006505, 0x1d00ffff, 2083236893),
+            Self::Testnet3 => (1296688602, 0x1d00ffff, 414098458),
+            Self::Testnet4 => (1714777860, 0x1d00ffff, 393743547),
+            Self::Signet => (1598918400, 0x1e0377ae, 52613770),
+            Self::Regtest => (1296688602, 0x207fffff, 2),
+        };
+        let merkle_root = match self {
+            Self::Testnet4 => "7aa0a7ae1e223414cb807e40cd57e667b718e42aaf9306db9102fe28912b7b4e",
+            _ => "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+        };
+        BlockHeader {
+            version: 1,
+            prev_blockhash: Hash256::ZERO,
+            merkle_root: merkle_root.parse().expect("merkle roots are valid hex"),
+            time,
+            bits,
+            nonce,
+        }
+    }
+
+    /// Easiest target a block may have.
+    pub fn pow_limit(&self) -> U256 {
+        let limit = match self {
+            Self::Mainnet | Self::Testnet3 | Self::Testnet4 => {
+                "00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff"
+            }
+            Self::Signet => "00000377ae000000000000000000000000000000000000000000000000000000",
+            Self::Regtest => "7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+        };
+        U256::from_be_hex(limit).expect("pow limits are valid hex")
+    }
+
+    /// Whether a block more than 20 minutes after its parent may use the minimum difficulty.
+    pub fn allow_min_difficulty_blocks(&self) -> bool {
+        matches!(self, Self::Testnet3 | Self::Testnet4 | Self::Regtest)
+    }
+
+    pub fn no_retargeting(&self) -> bool {
+        matches!(self, Self::Regtest)
+    }
+
+    /// BIP94: retargets start from the first block of the period, not a possibly min-difficulty
+    /// last one.
+    pub fn enforce_bip94(&self) -> bool {
+        matches!(self, Self::Testnet4)
+    }
+
+    /// Known good block hashes by height, headers conflicting with them are rejected.
+    pub fn checkpoints(&self) -> Vec<(u32, Hash256)> {
+        let checkpoints: &[(u32, &str)] = match self {
+            Self::Mainnet => &[
+                (
+                    11111,
+                    "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d",
+                ),
+                (
+                    33333,
+                    "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6",
+                ),
+                (
+                    74000,
+                    "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20",
+                ),
+                (
+                    105000,
+                    "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97",
+                ),
+                (
+                    134444,
+                    "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe",
+                ),
+                (
+                    168000,
+                    "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763",
+                ),
+                (
+                    193000,
+                    "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317",
+                ),
+                (
+                    210000,
+                    "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e",
+                ),
+                (
+                    216116,
+                    "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e",
+                ),
+                (
+                    225430,
+                    "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932",
+                ),
+                (
+                    250000,
+                    "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214",
+                ),
+                (
+                    279000,
+                    "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40",
+                ),
+                (
+                    295000,
+                    "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983",
+                ),
+            ],
+            Self::Testnet3 => &[(
+                546,
+                "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70",
+            )],
+            Self::Testnet4 | Self::Signet | Self::Regtest => &[],
+        };
+        checkpoints
+            .iter()
+            .map(|(height, hash)| (*height, hash.parse().expect("checkpoints are valid hex")))
+            .collect()
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Mainnet => "mainnet",
+            Self::Testnet3 => "testnet3",
+            Self::Testnet4 => "testnet4",
+            Self::Signet => "signet",
+            Self::Regtest => "regtest",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Network {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mainnet" | "main" | "bitcoin" => Ok(Self::Mainnet),
+            "testnet3" | "testnet" | "test" => Ok(Self::Testnet3),
+            "testnet4" => Ok(Self::Testnet4),
+            "signet" => Ok(Self::Signet),
+            "regtest" => Ok(Self::Regtest),
+            x => Err(Error::UnknownNetwork(x.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn magic_wire_bytes() {
+        assert_eq!(
+            Network::Mainnet.magic().to_le_bytes(),
+            [0xf9, 0xbe, 0xb4, 0xd9]
+        );
+        assert_eq!(
+            Network::Testnet3.magic().to_le_bytes(),
+            [0x0b, 0x11, 0x09, 0x07]
+        );
+        assert_eq!(
+            Network::Testnet4.magic().to_le_bytes(),
+            [0x1c, 0x16, 0x3f, 0x28]
+        );
+        assert_eq!(
+            Network::Signet.magic().to_le_bytes(),
+            [0x0a, 0x03, 0xcf, 0x40]
+        );
+        assert_eq!(
+            Network::Regtest.magic().to_le_bytes(),
+            [0xfa, 0xbf, 0xb5, 0xda]
+        );
+    }
+
+    #[test]
+    fn genesis_headers_hash_to_genesis() {
+        for network in [
+            Network::Mainnet,
+            Network::Testnet3,
+            Network::Testnet4,
+            Network::Signet,
+            Network::Regtest,
+        ] {
+            let genesis = network.genesis_header();
+            assert_eq!(genesis.hash(), network.genesis_hash(), "{network}");
+            assert!(U256::from(genesis.hash()) <= U256::from_compact(genesis.bits).0);
+        }
+    }
+
+    #[test]
+    fn parse_display_roundtrip() {
+        for network in [
+            Network::Mainnet,
+            Network::Testnet3,
+            Network::Testnet4,
+            Network::Signet,
+            Network::Regtest,
+        ] {
+            assert_eq!(network.to_string().parse::<Network>().unwrap(), network);
+        }
+    }
+}
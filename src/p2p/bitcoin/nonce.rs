@@ -0,0 +1,104 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// Nonces of the `version` messages we sent on connections that are still open.
+///
+/// A peer echoing one of them back is ourselves, the way Bitcoin Core detects self connections.
+/// Clones share the same set, so one registry covers every connection made with clones of a
+/// [`HandshakeConfig`](super::HandshakeConfig), inbound and outbound alike. Each nonce counts the
+/// connections using it, since a fixed [`HandshakeConfig::nonce`](super::HandshakeConfig::nonce)
+/// is shared by all of them.
+#[derive(Debug, Clone, Default)]
+pub struct NonceRegistry(Arc<Mutex<HashMap<u64, usize>>>);
+
+/// Keeps a nonce registered until dropped.
+#[derive(Debug)]
+pub(super) struct NonceGuard {
+    registry: NonceRegistry,
+    nonce: u64,
+}
+
+impl NonceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, nonce: u64) -> bool {
+        self.0.lock().unwrap().contains_key(&nonce)
+    }
+
+    /// Registers `nonce`, or a fresh random one not in use yet. Zero is what old clients send,
+    /// it is replaced too.
+    pub(super) fn register(&self, nonce: Option<u64>) -> NonceGuard {
+        let mut nonces = self.0.lock().unwrap();
+        let nonce = nonce.filter(|&nonce| nonce != 0).unwrap_or_else(|| loop {
+            let nonce = rand::random();
+            if nonce != 0 && !nonces.contains_key(&nonce) {
+                break nonce;
+            }
+        });
+        *nonces.entry(nonce).or_default() += 1;
+        NonceGuard {
+            registry: self.clone(),
+            nonce,
+        }
+    }
+}
+
+impl NonceGuard {
+    pub(super) fn nonce(&self) -> u64 {
+        self.nonce
+    }
+}
+
+impl Drop for NonceGuard {
+    fn drop(&mut self) {
+        let mut nonces = self.registry.0.lock().unwrap();
+        if let Some(count) = nonces.get_mut(&self.nonce) {
+            *count -= 1;
+            if *count == 0 {
+                nonces.remove(&self.nonce);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nonces_live_as_long_as_their_guard() {
+        let registry = NonceRegistry::new();
+        let shared = registry.clone();
+        let first = registry.register(None);
+        let second = registry.register(Some(7));
+        assert_ne!(first.nonce(), 0);
+        assert!(shared.contains(first.nonce()) && shared.contains(7));
+
+        drop(second);
+        assert!(!shared.contains(7));
+        assert!(shared.contains(first.nonce()));
+    }
+
+    #[test]
+    fn fixed_nonce_shared_by_connections() {
+        let registry = NonceRegistry::new();
+        let first = registry.register(Some(7));
+        let second = registry.register(Some(7));
+        drop(first);
+        assert!(registry.contains(7));
+        drop(second);
+        assert!(!registry.contains(7));
+    }
+
+    #[test]
+    fn zero_nonce_is_replaced() {
+        let registry = NonceRegistry::new();
+        let guard = registry.register(Some(0));
+        assert_ne!(guard.nonce(), 0);
+        assert!(!registry.contains(0));
+    }
+}
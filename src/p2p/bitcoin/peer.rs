@@ -0,0 +1,133 @@
+use super::{
+    compact::{SendCmpct, CMPCT_VERSION_WTXID},
+    protocol::{Payload, VersionMessage},
+    services::ServiceFlags,
+};
+use std::net::SocketAddr;
+
+/// What the peer told us about itself in its `version` message, plus the features negotiated
+/// since.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PeerInfo {
+    pub address: SocketAddr,
+    pub version: i32,
+    pub services: ServiceFlags,
+    pub user_agent: String,
+    pub start_height: i32,
+    pub relay: bool,
+    /// Seconds the peer's clock is ahead of ours, negative when it is behind.
+    pub clock_offset: i64,
+    pub features: Features,
+}
+
+/// Optional protocol features, only `wtxid_relay` is final once the handshake is done.
+///
+/// Peers usually send `sendheaders` and `sendcmpct` right after their verack, those fields are
+/// updated as the messages arrive.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Features {
+    /// Both sides sent wtxidrelay (BIP339).
+    pub wtxid_relay: bool,
+    /// The peer sent sendaddrv2 and accepts `addrv2` (BIP155).
+    pub addr_v2: bool,
+    /// The peer wants new blocks announced with `headers` (BIP130).
+    pub send_headers: bool,
+    /// The peer's last `sendcmpct` for the wtxid based version of compact blocks (BIP152).
+    pub compact_blocks: Option<SendCmpct>,
+}
+
+impl PeerInfo {
+    /// `now` is our UNIX time when the version arrived.
+    pub(super) fn new(address: SocketAddr, version: &VersionMessage, now: i64) -> Self {
+        Self {
+            address,
+            version: version.version,
+            services: version.services,
+            user_agent: version.user_agent.as_str().to_string(),
+            start_height: version.start_height,
+            relay: version.relay.unwrap_or(true),
+            clock_offset: version.timestamp.saturating_sub(now),
+            features: Features::default(),
+        }
+    }
+}
+
+impl Features {
+    /// Name of the first feature negotiated before verack that `required` asks for and we lack.
+    pub(super) fn missing(&self, required: &Features) -> Option<&'static str> {
+        if required.wtxid_relay && !self.wtxid_relay {
+            Some("wtxidrelay")
+        } else if required.addr_v2 && !self.addr_v2 {
+            Some("sendaddrv2")
+        } else {
+            None
+        }
+    }
+
+    /// Records the feature announced by `payload`, returns whether it was one.
+    pub(super) fn update(&mut self, payload: &Payload) -> bool {
+        match payload {
+            Payload::SendAddrV2 => self.addr_v2 = true,
+            Payload::SendHeaders => self.send_headers = true,
+            Payload::SendCmpct(sendcmpct) if sendcmpct.version == CMPCT_VERSION_WTXID => {
+                self.compact_blocks = Some(*sendcmpct)
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::bitcoin::{compact::CMPCT_VERSION_TXID, protocol::Address, NODE_NETWORK};
+
+    #[test]
+    fn version_and_features() {
+        let unspecified = Address {
+            time: (),
+            services: NODE_NETWORK,
+            ip: "::".parse().unwrap(),
+            port: 0.into(),
+        };
+        let version = VersionMessage {
+            version: 70016,
+            services: NODE_NETWORK,
+            timestamp: 1_700_000_000,
+            addr_recv: unspecified.clone(),
+            addr_from: unspecified,
+            nonce: 0,
+            user_agent: "/Satoshi:27.0.0/".into(),
+            start_height: 840_000,
+            relay: Some(true),
+        };
+        let address = "203.0.113.7:8333".parse().unwrap();
+        let mut info = PeerInfo::new(address, &version, 1_700_000_030);
+        assert_eq!(info.user_agent, "/Satoshi:27.0.0/");
+        assert_eq!(info.clock_offset, -30);
+        let skewed = VersionMessage {
+            timestamp: i64::MIN,
+            ..version.clone()
+        };
+        assert_eq!(PeerInfo::new(address, &skewed, 1).clock_offset, i64::MIN);
+
+        let features = &mut info.features;
+        let sendcmpct = |version| {
+            Payload::SendCmpct(SendCmpct {
+                announce: false,
+                version,
+            })
+        };
+        assert!(features.update(&Payload::SendHeaders));
+        assert!(!features.update(&sendcmpct(CMPCT_VERSION_TXID)));
+        assert_eq!(features.compact_blocks, None);
+        assert!(features.update(&sendcmpct(CMPCT_VERSION_WTXID)));
+        assert!(!features.update(&Payload::VerAck));
+        assert!(features.send_headers && !features.addr_v2);
+        assert_eq!(
+            features.compact_blocks.map(|c| c.version),
+            Some(CMPCT_VERSION_WTXID)
+        );
+    }
+}
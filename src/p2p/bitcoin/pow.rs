@@ -0,0 +1,276 @@
+//! Proof of work arithmetic: 256-bit targets, their compact `bits` encoding and chain work.
+
+use super::hashes::Hash256;
+use std::{
+    cmp::Ordering,
+    ops::{Add, Div, Not, Shl, Shr, Sub},
+};
+
+/// Unsigned 256-bit integer, four little-endian 64-bit limbs.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct U256([u64; 4]);
+
+impl U256 {
+    pub const ZERO: Self = Self([0; 4]);
+    pub const ONE: Self = Self([1, 0, 0, 0]);
+    pub const MAX: Self = Self([u64::MAX; 4]);
+
+    pub fn from_u64(value: u64) -> Self {
+        Self([value, 0, 0, 0])
+    }
+
+    /// Interprets 32 bytes as a little-endian number, the way block hashes are compared.
+    pub fn from_le_bytes(bytes: [u8; 32]) -> Self {
+        let mut limbs = [0; 4];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks(8)) {
+            *limb = u64::from_le_bytes(chunk.try_into().expect("chunks of 8"));
+        }
+        Self(limbs)
+    }
+
+    /// Parses big-endian hex, as targets are usually written.
+    pub fn from_be_hex(hex: &str) -> Option<Self> {
+        let mut bytes: [u8; 32] = hex::decode(hex).ok()?.try_into().ok()?;
+        bytes.reverse();
+        Some(Self::from_le_bytes(bytes))
+    }
+
+    pub fn low_u64(&self) -> u64 {
+        self.0[0]
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 4]
+    }
+
+    /// Number of significant bits.
+    pub fn bits(&self) -> u32 {
+        for (i, limb) in self.0.iter().enumerate().rev() {
+            if *limb != 0 {
+                return 64 * i as u32 + (64 - limb.leading_zeros());
+            }
+        }
+        0
+    }
+
+    pub fn checked_mul_u64(self, rhs: u64) -> Option<Self> {
+        let mut result = [0; 4];
+        let mut carry = 0_u128;
+        for (out, limb) in result.iter_mut().zip(self.0) {
+            let product = limb as u128 * rhs as u128 + carry;
+            *out = product as u64;
+            carry = product >> 64;
+        }
+        (carry == 0).then_some(Self(result))
+    }
+
+    pub fn div_u64(self, rhs: u64) -> Self {
+        let mut result = [0; 4];
+        let mut remainder = 0_u128;
+        for i in (0..4).rev() {
+            let current = (remainder << 64) | self.0[i] as u128;
+            result[i] = (current / rhs as u128) as u64;
+            remainder = current % rhs as u128;
+        }
+        Self(result)
+    }
+
+    /// Decodes compact `bits`, returning the target along with Bitcoin Core's negative and
+    /// overflow flags.
+    pub fn from_compact(bits: u32) -> (Self, bool, bool) {
+        let size = bits >> 24;
+        let mut word = bits & 0x007f_ffff;
+        let value = if size <= 3 {
+            word >>= 8 * (3 - size);
+            Self::from_u64(word as u64)
+        } else {
+            Self::from_u64(word as u64) << (8 * (size - 3))
+        };
+        let negative = word != 0 && (bits & 0x0080_0000) != 0;
+        let overflow =
+            word != 0 && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32));
+        (value, negative, overflow)
+    }
+
+    pub fn to_compact(self) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut compact = if size <= 3 {
+            (self.low_u64() << (8 * (3 - size))) as u32
+        } else {
+            (self >> (8 * (size - 3))).low_u64() as u32
+        };
+        // The sign bit is set, use one more byte of exponent instead
+        if compact & 0x0080_0000 != 0 {
+            compact >>= 8;
+            size += 1;
+        }
+        compact | (size << 24)
+    }
+
+    /// Expected number of hashes to find a block at this target, `2^256 / (target + 1)`.
+    pub fn work(&self) -> Self {
+        // 2^256 doesn't fit, but (2^256 - target - 1) / (target + 1) + 1 is the same thing
+        (!*self / (*self + Self::ONE)) + Self::ONE
+    }
+}
+
+impl From<Hash256> for U256 {
+    fn from(hash: Hash256) -> Self {
+        Self::from_le_bytes(hash.0)
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Not for U256 {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        Self(self.0.map(|limb| !limb))
+    }
+}
+
+impl Add for U256 {
+    type Output = Self;
+
+    /// Wrapping addition, chain work never gets anywhere near overflowing.
+    fn add(self, rhs: Self) -> Self {
+        let mut result = [0; 4];
+        let mut carry = false;
+        for (i, out) in result.iter_mut().enumerate() {
+            let (sum, first) = self.0[i].overflowing_add(rhs.0[i]);
+            let (sum, second) = sum.overflowing_add(carry as u64);
+            *out = sum;
+            carry = first || second;
+        }
+        Self(result)
+    }
+}
+
+impl Sub for U256 {
+    type Output = Self;
+
+    /// Wrapping subtraction.
+    fn sub(self, rhs: Self) -> Self {
+        self + (!rhs + Self::ONE)
+    }
+}
+
+impl Shl<u32> for U256 {
+    type Output = Self;
+
+    fn shl(self, shift: u32) -> Self {
+        let mut result = [0; 4];
+        let (limbs, bits) = ((shift / 64) as usize, shift % 64);
+        for (i, out) in result.iter_mut().enumerate().skip(limbs) {
+            *out = self.0[i - limbs] << bits;
+            if bits > 0 && i > limbs {
+                *out |= self.0[i - limbs - 1] >> (64 - bits);
+            }
+        }
+        Self(result)
+    }
+}
+
+impl Shr<u32> for U256 {
+    type Output = Self;
+
+    fn shr(self, shift: u32) -> Self {
+        let mut result = [0; 4];
+        let (limbs, bits) = ((shift / 64) as usize, shift % 64);
+        for (i, out) in result
+            .iter_mut()
+            .take(4_usize.saturating_sub(limbs))
+            .enumerate()
+        {
+            *out = self.0[i + limbs] >> bits;
+            if bits > 0 && i + limbs + 1 < 4 {
+                *out |= self.0[i + limbs + 1] << (64 - bits);
+            }
+        }
+        Self(result)
+    }
+}
+
+impl Div for U256 {
+    type Output = Self;
+
+    /// Long division, panics on division by zero like the primitive integers.
+    fn div(self, rhs: Self) -> Self {
+        assert!(!rhs.is_zero(), "division by zero");
+        if rhs > self {
+            return Self::ZERO;
+        }
+        let shift = self.bits() - rhs.bits();
+        let mut divisor = rhs << shift;
+        let mut remainder = self;
+        let mut quotient = Self::ZERO;
+        for i in (0..=shift).rev() {
+            if remainder >= divisor {
+                remainder = remainder - divisor;
+                quotient.0[(i / 64) as usize] |= 1 << (i % 64);
+            }
+            divisor = divisor >> 1;
+        }
+        quotient
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_roundtrip() {
+        let (target, negative, overflow) = U256::from_compact(0x1d00ffff);
+        assert!(!negative && !overflow);
+        assert_eq!(
+            target,
+            U256::from_be_hex("00000000ffff0000000000000000000000000000000000000000000000000000")
+                .unwrap()
+        );
+        assert_eq!(target.to_compact(), 0x1d00ffff);
+
+        // Values from Bitcoin Core's arith_uint256 tests
+        assert_eq!(U256::from_compact(0x01123456).0, U256::from_u64(0x12));
+        assert_eq!(U256::from_compact(0x01123456).0.to_compact(), 0x01120000);
+        assert_eq!(
+            U256::from_compact(0x04923456),
+            (U256::from_u64(0x12345600), true, false)
+        );
+        assert_eq!(U256::from_u64(0x80).to_compact(), 0x02008000);
+        assert!(U256::from_compact(0xff123456).2);
+    }
+
+    #[test]
+    fn genesis_work() {
+        // Chain work of the genesis block is 0x100010001
+        let (target, _, _) = U256::from_compact(0x1d00ffff);
+        assert_eq!(target.work(), U256::from_u64(0x0000_0001_0001_0001));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let value = U256::from_u64(u64::MAX);
+        assert_eq!((value + U256::ONE), U256([0, 1, 0, 0]));
+        assert_eq!((value + U256::ONE) - U256::ONE, value);
+        assert_eq!((U256::ONE << 200) >> 200, U256::ONE);
+        assert_eq!((U256::ONE << 200) / (U256::ONE << 100), U256::ONE << 100);
+        assert_eq!(U256::from_u64(1000).div_u64(7), U256::from_u64(142));
+        assert_eq!(
+            U256::from_u64(3).checked_mul_u64(u64::MAX),
+            Some(U256([u64::MAX - 2, 2, 0, 0]))
+        );
+        assert_eq!(U256::MAX.checked_mul_u64(2), None);
+    }
+}
@@ -1,5 +1,20 @@
-use super::{hashes::Checksum, Decode, Encode, Error, Result};
-use bytes::{Buf, BufMut, BytesMut};
+use super::{
+    addr::AddressV2,
+    block::{Block, BlockHeader, GetHeadersMessage, HeadersEntry, MAX_HEADERS_RESULTS},
+    bloom::{BloomFilter, FilterAdd, MerkleBlock},
+    compact::{BlockTransactions, BlockTransactionsRequest, HeaderAndShortIds, SendCmpct},
+    decode::{decode_list, MAX_SIZE},
+    encode::{encode_list, VariableInt},
+    filter::{CFCheckpt, CFHeaders, CFilter, FilterRequest, GetCFCheckpt},
+    hashes::Checksum,
+    inventory::{InvVector, MAX_INV_SZ},
+    network::Network,
+    services::ServiceFlags,
+    transaction::Transaction,
+    Decode, Encode, Error, Result,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fmt;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Message {
@@ -11,12 +26,12 @@ pub struct Message {
 }
 
 impl Message {
-    pub fn new(magic: u32, command: Command, payload: Payload) -> Self {
+    pub fn new(network: Network, command: Command, payload: Payload) -> Self {
         let mut payload_encoded = BytesMut::new();
         let length = payload.encode(&mut payload_encoded) as u32;
         let checksum = payload_encoded.sha256();
         Self {
-            magic,
+            magic: network.magic(),
             command,
             length,
             checksum,
@@ -24,6 +39,38 @@ impl Message {
         }
     }
 
+    /// Message from a payload that arrived without a v1 header, as in the v2 transport.
+    pub(super) fn from_payload(
+        network: Network,
+        command: Command,
+        mut payload_bytes: BytesMut,
+    ) -> Result<Self> {
+        let length = payload_bytes.len() as u32;
+        let checksum = payload_bytes.sha256();
+        let payload = Payload::decode_command(&command, &mut payload_bytes)?;
+        if !payload_bytes.is_empty() {
+            return Err(Error::PayloadLength {
+                expected: length,
+                actual: length - payload_bytes.len() as u32,
+            });
+        }
+        Ok(Self {
+            magic: network.magic(),
+            command,
+            length,
+            checksum,
+            payload,
+        })
+    }
+
+    pub fn magic(&self) -> u32 {
+        self.magic
+    }
+
+    pub fn command(&self) -> &Command {
+        &self.command
+    }
+
     pub fn payload(&self) -> &Payload {
         &self.payload
     }
@@ -40,13 +87,38 @@ impl Encode for Message {
     }
 }
 
-impl Decode for Message {
-    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+impl Message {
+    /// Decodes a v1 frame, which has to carry the magic of `network`.
+    pub fn decode(bytes: &mut BytesMut, network: Network) -> Result<Self> {
         let magic = u32::decode(bytes)?;
+        if magic != network.magic() {
+            return Err(Error::BadMagic {
+                expected: network.magic(),
+                actual: magic,
+            });
+        }
         let command = Command::decode(bytes)?;
         let length = u32::decode(bytes)?;
         let checksum = u32::decode(bytes)?;
-        let payload = Payload::decode_command(&command, bytes)?;
+        if bytes.remaining() < length as usize {
+            return Err(Error::NotEnoughBytes("payload"));
+        }
+        // Split the payload off first, so a bad frame never eats into the next one
+        let mut payload_bytes = bytes.split_to(length as usize);
+        let actual = payload_bytes.sha256();
+        if actual != checksum {
+            return Err(Error::ChecksumMismatch {
+                expected: checksum,
+                actual,
+            });
+        }
+        let payload = Payload::decode_command(&command, &mut payload_bytes)?;
+        if !payload_bytes.is_empty() {
+            return Err(Error::PayloadLength {
+                expected: length,
+                actual: length - payload_bytes.len() as u32,
+            });
+        }
         Ok(Message {
             magic,
             command,
@@ -57,30 +129,90 @@ impl Decode for Message {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Command {
     Version,
     VerAck,
     SendHeaders,
     WtxIdRelay,
     SendAddrV2,
+    Ping,
+    Pong,
+    Addr,
+    AddrV2,
+    GetAddr,
+    Inv,
+    GetData,
+    NotFound,
+    GetHeaders,
+    Headers,
+    Tx,
+    Block,
+    SendCmpct,
+    CmpctBlock,
+    GetBlockTxn,
+    BlockTxn,
+    GetCFilters,
+    CFilter,
+    GetCFHeaders,
+    CFHeaders,
+    GetCFCheckpt,
+    CFCheckpt,
+    FilterLoad,
+    FilterAdd,
+    FilterClear,
+    MerkleBlock,
+    /// Any command we don't parse, kept as the raw null-padded name
+    Unknown([u8; 12]),
 }
 
 impl Command {
     fn decode(bytes: &mut BytesMut) -> Result<Self> {
-        let decode = match &bytes[..12] {
-            b"version\0\0\0\0\0" => Ok(Command::Version),
-            b"verack\0\0\0\0\0\0" => Ok(Command::VerAck),
-            b"wtxidrelay\0\0" => Ok(Command::WtxIdRelay),
-            b"sendheaders\0" => Ok(Command::SendHeaders),
-            b"sendaddrv2\0\0" => Ok(Command::SendAddrV2),
-            x => Err(Error::Command(format!(
-                "unhandled command: {:?}",
-                String::from_utf8_lossy(x)
-            ))),
-        };
+        if bytes.remaining() < 12 {
+            return Err(Error::NotEnoughBytes("command"));
+        }
+        let decode = Command::from(<[u8; 12]>::try_from(&bytes[..12])?);
         bytes.advance(12);
-        decode
+        Ok(decode)
+    }
+}
+
+impl From<[u8; 12]> for Command {
+    fn from(name: [u8; 12]) -> Self {
+        match &name {
+            b"version\0\0\0\0\0" => Command::Version,
+            b"verack\0\0\0\0\0\0" => Command::VerAck,
+            b"wtxidrelay\0\0" => Command::WtxIdRelay,
+            b"sendheaders\0" => Command::SendHeaders,
+            b"sendaddrv2\0\0" => Command::SendAddrV2,
+            b"ping\0\0\0\0\0\0\0\0" => Command::Ping,
+            b"pong\0\0\0\0\0\0\0\0" => Command::Pong,
+            b"addr\0\0\0\0\0\0\0\0" => Command::Addr,
+            b"addrv2\0\0\0\0\0\0" => Command::AddrV2,
+            b"getaddr\0\0\0\0\0" => Command::GetAddr,
+            b"inv\0\0\0\0\0\0\0\0\0" => Command::Inv,
+            b"getdata\0\0\0\0\0" => Command::GetData,
+            b"notfound\0\0\0\0" => Command::NotFound,
+            b"getheaders\0\0" => Command::GetHeaders,
+            b"headers\0\0\0\0\0" => Command::Headers,
+            b"tx\0\0\0\0\0\0\0\0\0\0" => Command::Tx,
+            b"block\0\0\0\0\0\0\0" => Command::Block,
+            b"sendcmpct\0\0\0" => Command::SendCmpct,
+            b"cmpctblock\0\0" => Command::CmpctBlock,
+            b"getblocktxn\0" => Command::GetBlockTxn,
+            b"blocktxn\0\0\0\0" => Command::BlockTxn,
+            b"getcfilters\0" => Command::GetCFilters,
+            b"cfilter\0\0\0\0\0" => Command::CFilter,
+            b"getcfheaders" => Command::GetCFHeaders,
+            b"cfheaders\0\0\0" => Command::CFHeaders,
+            b"getcfcheckpt" => Command::GetCFCheckpt,
+            b"cfcheckpt\0\0\0" => Command::CFCheckpt,
+            b"filterload\0\0" => Command::FilterLoad,
+            b"filteradd\0\0\0" => Command::FilterAdd,
+            b"filterclear\0" => Command::FilterClear,
+            b"merkleblock\0" => Command::MerkleBlock,
+            _ => Command::Unknown(name),
+        }
     }
 }
 
@@ -89,20 +221,92 @@ impl Encode for Command {
         match self {
             Self::Version => buffer.put_slice(b"version\0\0\0\0\0"),
             Self::VerAck => buffer.put_slice(b"verack\0\0\0\0\0\0"),
-            Self::WtxIdRelay => buffer.put_slice(b"wtxidrelay\0\0\0\0\0\0"),
+            Self::WtxIdRelay => buffer.put_slice(b"wtxidrelay\0\0"),
             Self::SendHeaders => buffer.put_slice(b"sendheaders\0"),
             Self::SendAddrV2 => buffer.put_slice(b"sendaddrv2\0\0"),
+            Self::Ping => buffer.put_slice(b"ping\0\0\0\0\0\0\0\0"),
+            Self::Pong => buffer.put_slice(b"pong\0\0\0\0\0\0\0\0"),
+            Self::Addr => buffer.put_slice(b"addr\0\0\0\0\0\0\0\0"),
+            Self::AddrV2 => buffer.put_slice(b"addrv2\0\0\0\0\0\0"),
+            Self::GetAddr => buffer.put_slice(b"getaddr\0\0\0\0\0"),
+            Self::Inv => buffer.put_slice(b"inv\0\0\0\0\0\0\0\0\0"),
+            Self::GetData => buffer.put_slice(b"getdata\0\0\0\0\0"),
+            Self::NotFound => buffer.put_slice(b"notfound\0\0\0\0"),
+            Self::GetHeaders => buffer.put_slice(b"getheaders\0\0"),
+            Self::Headers => buffer.put_slice(b"headers\0\0\0\0\0"),
+            Self::Tx => buffer.put_slice(b"tx\0\0\0\0\0\0\0\0\0\0"),
+            Self::Block => buffer.put_slice(b"block\0\0\0\0\0\0\0"),
+            Self::SendCmpct => buffer.put_slice(b"sendcmpct\0\0\0"),
+            Self::CmpctBlock => buffer.put_slice(b"cmpctblock\0\0"),
+            Self::GetBlockTxn => buffer.put_slice(b"getblocktxn\0"),
+            Self::BlockTxn => buffer.put_slice(b"blocktxn\0\0\0\0"),
+            Self::GetCFilters => buffer.put_slice(b"getcfilters\0"),
+            Self::CFilter => buffer.put_slice(b"cfilter\0\0\0\0\0"),
+            Self::GetCFHeaders => buffer.put_slice(b"getcfheaders"),
+            Self::CFHeaders => buffer.put_slice(b"cfheaders\0\0\0"),
+            Self::GetCFCheckpt => buffer.put_slice(b"getcfcheckpt"),
+            Self::CFCheckpt => buffer.put_slice(b"cfcheckpt\0\0\0"),
+            Self::FilterLoad => buffer.put_slice(b"filterload\0\0"),
+            Self::FilterAdd => buffer.put_slice(b"filteradd\0\0\0"),
+            Self::FilterClear => buffer.put_slice(b"filterclear\0"),
+            Self::MerkleBlock => buffer.put_slice(b"merkleblock\0"),
+            Self::Unknown(name) => buffer.put_slice(name),
         };
         12
     }
 }
 
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut name = BytesMut::new();
+        self.encode(&mut name);
+        let name = String::from_utf8_lossy(&name);
+        f.write_str(name.trim_end_matches('\0'))
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Payload {
     Version(VersionMessage),
     VerAck,
     SendHeaders,
+    /// BIP339 request to announce transactions by wtxid
+    WtxIdRelay,
+    /// BIP31 ping carrying a nonce the peer must echo back
+    Ping(u64),
+    Pong(u64),
+    SendAddrV2,
+    Addr(Vec<Address<u32>>),
+    AddrV2(Vec<AddressV2>),
+    GetAddr,
+    Inv(Vec<InvVector>),
+    GetData(Vec<InvVector>),
+    NotFound(Vec<InvVector>),
+    GetHeaders(GetHeadersMessage),
+    Headers(Vec<BlockHeader>),
+    Tx(Transaction),
+    Block(Block),
+    /// BIP152 compact block negotiation
+    SendCmpct(SendCmpct),
+    CmpctBlock(HeaderAndShortIds),
+    GetBlockTxn(BlockTransactionsRequest),
+    BlockTxn(BlockTransactions),
+    /// BIP157 compact block filters
+    GetCFilters(FilterRequest),
+    CFilter(CFilter),
+    GetCFHeaders(FilterRequest),
+    CFHeaders(CFHeaders),
+    GetCFCheckpt(GetCFCheckpt),
+    CFCheckpt(CFCheckpt),
+    /// BIP37 bloom filtering
+    FilterLoad(BloomFilter),
+    FilterAdd(FilterAdd),
+    FilterClear,
+    /// Partial merkle tree, checked against the header's merkle root on decode
+    MerkleBlock(MerkleBlock),
     Empty,
+    /// Payload of a command we don't parse, passed through untouched
+    Raw(Bytes),
 }
 
 impl Payload {
@@ -113,9 +317,58 @@ impl Payload {
                 Ok(Payload::Version(version))
             }
             Command::VerAck => Ok(Payload::VerAck),
-            Command::WtxIdRelay => Ok(Payload::Empty),
+            Command::WtxIdRelay => Ok(Payload::WtxIdRelay),
             Command::SendHeaders => Ok(Payload::SendHeaders),
-            Command::SendAddrV2 => Ok(Payload::Empty),
+            Command::SendAddrV2 => Ok(Payload::SendAddrV2),
+            Command::Ping => Ok(Payload::Ping(u64::decode(bytes)?)),
+            Command::Pong => Ok(Payload::Pong(u64::decode(bytes)?)),
+            Command::Addr => Ok(Payload::Addr(decode_list(bytes, "addr", MAX_ADDR_TO_SEND)?)),
+            Command::AddrV2 => Ok(Payload::AddrV2(decode_list(
+                bytes,
+                "addrv2",
+                MAX_ADDR_TO_SEND,
+            )?)),
+            Command::GetAddr => Ok(Payload::GetAddr),
+            Command::Inv => Ok(Payload::Inv(decode_list(bytes, "inv", MAX_INV_SZ)?)),
+            Command::GetData => Ok(Payload::GetData(decode_list(bytes, "getdata", MAX_INV_SZ)?)),
+            Command::NotFound => Ok(Payload::NotFound(decode_list(
+                bytes, "notfound", MAX_INV_SZ,
+            )?)),
+            Command::GetHeaders => Ok(Payload::GetHeaders(GetHeadersMessage::decode(bytes)?)),
+            Command::Headers => {
+                let entries: Vec<HeadersEntry> =
+                    decode_list(bytes, "headers", MAX_HEADERS_RESULTS)?;
+                Ok(Payload::Headers(
+                    entries.into_iter().map(|entry| entry.0).collect(),
+                ))
+            }
+            Command::Tx => Ok(Payload::Tx(Transaction::decode(bytes)?)),
+            Command::Block => {
+                let block = Block::decode(bytes)?;
+                block.check_merkle_root()?;
+                Ok(Payload::Block(block))
+            }
+            Command::SendCmpct => Ok(Payload::SendCmpct(SendCmpct::decode(bytes)?)),
+            Command::CmpctBlock => Ok(Payload::CmpctBlock(HeaderAndShortIds::decode(bytes)?)),
+            Command::GetBlockTxn => Ok(Payload::GetBlockTxn(BlockTransactionsRequest::decode(
+                bytes,
+            )?)),
+            Command::BlockTxn => Ok(Payload::BlockTxn(BlockTransactions::decode(bytes)?)),
+            Command::GetCFilters => Ok(Payload::GetCFilters(FilterRequest::decode(bytes)?)),
+            Command::CFilter => Ok(Payload::CFilter(CFilter::decode(bytes)?)),
+            Command::GetCFHeaders => Ok(Payload::GetCFHeaders(FilterRequest::decode(bytes)?)),
+            Command::CFHeaders => Ok(Payload::CFHeaders(CFHeaders::decode(bytes)?)),
+            Command::GetCFCheckpt => Ok(Payload::GetCFCheckpt(GetCFCheckpt::decode(bytes)?)),
+            Command::CFCheckpt => Ok(Payload::CFCheckpt(CFCheckpt::decode(bytes)?)),
+            Command::FilterLoad => Ok(Payload::FilterLoad(BloomFilter::decode(bytes)?)),
+            Command::FilterAdd => Ok(Payload::FilterAdd(FilterAdd::decode(bytes)?)),
+            Command::FilterClear => Ok(Payload::FilterClear),
+            Command::MerkleBlock => {
+                let merkle_block = MerkleBlock::decode(bytes)?;
+                merkle_block.extract_matches()?;
+                Ok(Payload::MerkleBlock(merkle_block))
+            }
+            Command::Unknown(_) => Ok(Payload::Raw(bytes.split().freeze())),
         }
     }
 }
@@ -126,106 +379,71 @@ impl Encode for Payload {
             Self::Version(version) => version.encode(buffer),
             Self::VerAck => ().encode(buffer),
             Self::SendHeaders => ().encode(buffer),
+            Self::WtxIdRelay => ().encode(buffer),
+            Self::Ping(nonce) => nonce.encode(buffer),
+            Self::Pong(nonce) => nonce.encode(buffer),
+            Self::SendAddrV2 => ().encode(buffer),
+            Self::Addr(addresses) => encode_list(addresses, buffer),
+            Self::AddrV2(addresses) => encode_list(addresses, buffer),
+            Self::GetAddr => ().encode(buffer),
+            Self::Inv(inventory) => encode_list(inventory, buffer),
+            Self::GetData(inventory) => encode_list(inventory, buffer),
+            Self::NotFound(inventory) => encode_list(inventory, buffer),
+            Self::GetHeaders(getheaders) => getheaders.encode(buffer),
+            Self::Tx(transaction) => transaction.encode(buffer),
+            Self::Block(block) => block.encode(buffer),
+            Self::SendCmpct(sendcmpct) => sendcmpct.encode(buffer),
+            Self::CmpctBlock(compact) => compact.encode(buffer),
+            Self::GetBlockTxn(request) => request.encode(buffer),
+            Self::BlockTxn(transactions) => transactions.encode(buffer),
+            Self::GetCFilters(request) => request.encode(buffer),
+            Self::CFilter(cfilter) => cfilter.encode(buffer),
+            Self::GetCFHeaders(request) => request.encode(buffer),
+            Self::CFHeaders(cfheaders) => cfheaders.encode(buffer),
+            Self::GetCFCheckpt(request) => request.encode(buffer),
+            Self::CFCheckpt(cfcheckpt) => cfcheckpt.encode(buffer),
+            Self::FilterLoad(filter) => filter.encode(buffer),
+            Self::FilterAdd(element) => element.encode(buffer),
+            Self::FilterClear => ().encode(buffer),
+            Self::MerkleBlock(merkle_block) => merkle_block.encode(buffer),
+            Self::Headers(headers) => {
+                let entries: Vec<HeadersEntry> =
+                    headers.iter().copied().map(HeadersEntry).collect();
+                encode_list(&entries, buffer)
+            }
             Self::Empty => ().encode(buffer),
+            Self::Raw(bytes) => {
+                buffer.put_slice(bytes);
+                bytes.len()
+            }
         }
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Encode, Decode)]
 pub struct VersionMessage {
     pub version: i32,
-    pub services: u64,
+    pub services: ServiceFlags,
     pub timestamp: i64,
     pub addr_recv: Address<()>,
     pub addr_from: Address<()>,
     pub nonce: u64,
     pub user_agent: VariableLengthString,
     pub start_height: i32,
-    pub relay: bool,
-}
-
-impl Encode for VersionMessage {
-    fn encode(&self, buffer: &mut BytesMut) -> usize {
-        let mut written = self.version.encode(buffer);
-        written += self.services.encode(buffer);
-        written += self.timestamp.encode(buffer);
-        written += self.addr_recv.encode(buffer);
-        written += self.addr_from.encode(buffer);
-        written += self.nonce.encode(buffer);
-        written += self.user_agent.encode(buffer);
-        written += self.start_height.encode(buffer);
-        written += self.relay.encode(buffer);
-        written
-    }
+    /// Left out by peers older than BIP37 (70001), which relay every transaction
+    pub relay: Option<bool>,
 }
 
-impl Decode for VersionMessage {
-    fn decode(bytes: &mut BytesMut) -> Result<Self> {
-        let version = i32::decode(bytes)?;
-        let services = u64::decode(bytes)?;
-        let timestamp = i64::decode(bytes)?;
-        let addr_recv = Address::decode(bytes)?;
-        let addr_from = Address::decode(bytes)?;
-        let nonce = u64::decode(bytes)?;
-        let user_agent = VariableLengthString::decode(bytes)?;
-        let start_height = i32::decode(bytes)?;
-        let relay = bool::decode(bytes)?;
-        Ok(VersionMessage {
-            version,
-            services,
-            timestamp,
-            addr_recv,
-            addr_from,
-            nonce,
-            user_agent,
-            start_height,
-            relay,
-        })
-    }
-}
-
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Encode, Decode)]
 pub struct Address<T> {
     pub time: T,
-    pub services: u64,
+    pub services: ServiceFlags,
     pub ip: std::net::IpAddr,
     pub port: Port,
 }
 
-impl<T> Encode for Address<T>
-where
-    T: Encode,
-{
-    fn encode(&self, buffer: &mut BytesMut) -> usize {
-        let mut bytes = 0;
-        bytes += self.time.encode(buffer);
-        bytes += self.services.encode(buffer);
-        bytes += self.ip.encode(buffer);
-        bytes += self.port.encode(buffer);
-        bytes
-    }
-}
-
-impl<T> Decode for Address<T>
-where
-    T: Decode,
-{
-    fn decode(bytes: &mut BytesMut) -> Result<Self> {
-        let time = T::decode(bytes)?;
-        let services = u64::decode(bytes)?;
-        let ip = std::net::IpAddr::decode(bytes)?;
-        let port = Port::decode(bytes)?;
-        Ok(Address {
-            time,
-            services,
-            ip,
-            port,
-        })
-    }
-}
-
-#[derive(Clone, Debug, Default, Eq, PartialEq)]
-pub struct Port(u16);
+#[derive(Clone, Debug, Default, Eq, PartialEq, Encode, Decode)]
+pub struct Port(#[wire(big_endian)] u16);
 
 impl From<u16> for Port {
     fn from(port: u16) -> Self {
@@ -233,75 +451,18 @@ impl From<u16> for Port {
     }
 }
 
-impl Encode for Port {
-    fn encode(&self, buffer: &mut BytesMut) -> usize {
-        buffer.put_u16(self.0);
-        std::mem::size_of::<u16>()
-    }
-}
-
-impl Decode for Port {
-    fn decode(bytes: &mut BytesMut) -> Result<Self> {
-        if bytes.remaining() <= 2 {
-            return Err(Error::NotEnoughBytes("port"));
-        };
-        Ok(Self(bytes.get_u16()))
-    }
-}
+/// Bitcoin Core's `MAX_ADDR_TO_SEND`, the most entries an `addr` or `addrv2` may carry.
+pub const MAX_ADDR_TO_SEND: usize = 1000;
 
 #[derive(Debug, Default, Clone, Eq, PartialEq)]
-struct VariableInt(u64);
-
-impl Encode for VariableInt {
-    fn encode(&self, buffer: &mut BytesMut) -> usize {
-        match self.0 {
-            0..=0xFC => {
-                buffer.put_u8(self.0 as u8);
-                1
-            }
-            0xFD..=0xFFFF => {
-                buffer.put_u8(0xFD);
-                1 + (self.0 as u16).encode(buffer)
-            }
-            0x10000..=0xFFFFFFFF => {
-                buffer.put_u8(0xFE);
-                1 + (self.0 as u32).encode(buffer)
-            }
-            _ => {
-                buffer.put_u8(0xFE);
-                self.0.encode(buffer);
-                1 + self.0.encode(buffer)
-            }
-        }
-    }
-}
+pub struct VariableLengthString(VariableInt, String);
 
-impl Decode for VariableInt {
-    fn decode(bytes: &mut BytesMut) -> Result<Self> {
-        if bytes.remaining() < 1 {
-            return Err(Error::NotEnoughBytes("variable int"));
-        }
-        match bytes.get_u8() {
-            0xFD => {
-                let number = u16::decode(bytes)?;
-                Ok(VariableInt(number as u64))
-            }
-            0xFE => {
-                let number = u32::decode(bytes)?;
-                Ok(VariableInt(number as u64))
-            }
-            0xFF => {
-                let number = u64::decode(bytes)?;
-                Ok(VariableInt(number))
-            }
-            x => Ok(VariableInt(x as u64)),
-        }
+impl VariableLengthString {
+    pub fn as_str(&self) -> &str {
+        &self.1
     }
 }
 
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
-pub struct VariableLengthString(VariableInt, String);
-
 impl From<&str> for VariableLengthString {
     fn from(s: &str) -> Self {
         VariableLengthString(VariableInt(s.len() as u64), s.to_string())
@@ -318,13 +479,13 @@ impl Encode for VariableLengthString {
 
 impl Decode for VariableLengthString {
     fn decode(bytes: &mut BytesMut) -> Result<Self> {
-        let length = VariableInt::decode(bytes)?;
-        if bytes.remaining() < length.0 as usize {
+        let length = VariableInt::decode_length(bytes, "variable length string", MAX_SIZE)?;
+        if bytes.remaining() < length {
             return Err(Error::NotEnoughBytes("variable length string"));
         }
-        let str = String::from_utf8_lossy(&bytes[..length.0 as usize]).into_owned();
-        bytes.advance(length.0 as usize);
-        Ok(Self(length, str))
+        let str = String::from_utf8_lossy(&bytes[..length]).into_owned();
+        bytes.advance(length);
+        Ok(Self(VariableInt(length as u64), str))
     }
 }
 
@@ -337,30 +498,30 @@ mod tests {
     fn encode() {
         let message_bin = b"\xf9\xbe\xb4\xd9version\0\0\0\0\0f\0\0\0@e\xe2A\x80\x11\x01\0\t\x04\0\0\0\0\0\0\x0e\xb1$d\0\0\0\0\0\0\0\0\0\0\0\0*\x02\x83\x08\x90\x0cY\0\xb5\x9b\xb5Q\x1c&\x02\xa8\xdb~\t\x04\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0SH\x1f\xe5\xdc6S`\x10/Satoshi:23.0.0/\xe8\xf2\x0b\0\x01";
         let message = Message {
-            magic: 3652501241,
+            magic: Network::Mainnet.magic(),
             command: Command::Version,
             length: 102,
             checksum: 1105356096,
             payload: Payload::Version(VersionMessage {
                 version: 70016,
-                services: 1033,
+                services: 1033.into(),
                 timestamp: 1680126222,
                 addr_recv: Address {
                     time: (),
-                    services: 0,
+                    services: 0.into(),
                     ip: "2a02:8308:900c:5900:b59b:b551:1c26:2a8".parse().unwrap(),
                     port: Port(56190),
                 },
                 addr_from: Address {
                     time: (),
-                    services: 1033,
+                    services: 1033.into(),
                     ip: "::".parse().unwrap(),
                     port: Port(0),
                 },
                 nonce: 6940951773072803923,
                 user_agent: "/Satoshi:23.0.0/".into(),
                 start_height: 783080,
-                relay: true,
+                relay: Some(true),
             }),
         };
 
@@ -374,34 +535,34 @@ mod tests {
         let message_bin = b"\xf9\xbe\xb4\xd9version\0\0\0\0\0f\0\0\0@e\xe2A\x80\x11\x01\0\t\x04\0\0\0\0\0\0\x0e\xb1$d\0\0\0\0\0\0\0\0\0\0\0\0*\x02\x83\x08\x90\x0cY\0\xb5\x9b\xb5Q\x1c&\x02\xa8\xdb~\t\x04\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0SH\x1f\xe5\xdc6S`\x10/Satoshi:23.0.0/\xe8\xf2\x0b\0\x01\xf9\xbe\xb4\xd9verack\0\0\0\0\0\0\0\0\0\0]\xf6\xe0\xe2";
         let mut bytes = BytesMut::new();
         bytes.extend_from_slice(&message_bin[..]);
-        let message = Message::decode(&mut bytes).unwrap();
+        let message = Message::decode(&mut bytes, Network::Mainnet).unwrap();
         assert_eq!(
             message,
             Message {
-                magic: 3652501241,
+                magic: Network::Mainnet.magic(),
                 command: Command::Version,
                 length: 102,
                 checksum: 1105356096,
                 payload: Payload::Version(VersionMessage {
                     version: 70016,
-                    services: 1033,
+                    services: 1033.into(),
                     timestamp: 1680126222,
                     addr_recv: Address {
                         time: (),
-                        services: 0,
+                        services: 0.into(),
                         ip: "2a02:8308:900c:5900:b59b:b551:1c26:2a8".parse().unwrap(),
                         port: Port(56190),
                     },
                     addr_from: Address {
                         time: (),
-                        services: 1033,
+                        services: 1033.into(),
                         ip: "::".parse().unwrap(),
                         port: Port(0),
                     },
                     nonce: 6940951773072803923,
                     user_agent: "/Satoshi:23.0.0/".into(),
                     start_height: 783080,
-                    relay: true,
+                    relay: Some(true),
                 })
             }
         );
@@ -409,64 +570,392 @@ mod tests {
 
     #[test]
     fn encode_decode() {
-        let msg = Message {
-            magic: 3652501241,
-            command: Command::Version,
-            length: 102,
-            checksum: 1105356096,
-            payload: Payload::Version(VersionMessage {
+        let msg = Message::new(
+            Network::Mainnet,
+            Command::Version,
+            Payload::Version(VersionMessage {
                 version: 70016,
-                services: 1033,
+                services: 1033.into(),
                 timestamp: 1680126222,
                 addr_recv: Address {
                     time: (),
-                    services: 0,
+                    services: 0.into(),
                     ip: "2a02:8308:900c:5900:b59b:b551:1c26:2a8".parse().unwrap(),
                     port: Port(56190),
                 },
                 addr_from: Address {
                     time: (),
-                    services: 1033,
+                    services: 1033.into(),
                     ip: "::192.168.0.1".parse().unwrap(),
                     port: Port(0),
                 },
                 nonce: 6920951773072803923,
                 user_agent: "/Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto/".into(),
                 start_height: 1932515342,
-                relay: true,
-            })
-        };
+                relay: Some(true),
+            }),
+        );
 
         let mut buf = BytesMut::new();
         let _ = msg.encode(&mut buf);
-        let decoded = Message::decode(&mut buf).unwrap();
+        let decoded = Message::decode(&mut buf, Network::Mainnet).unwrap();
 
         assert_eq!(decoded, msg);
     }
 
+    #[test]
+    fn decode_bad_magic() {
+        let message = Message::new(Network::Testnet4, Command::VerAck, Payload::VerAck);
+        let mut bytes = BytesMut::new();
+        message.encode(&mut bytes);
+        assert!(matches!(
+            Message::decode(&mut bytes.clone(), Network::Mainnet),
+            Err(Error::BadMagic { actual, .. }) if actual == Network::Testnet4.magic()
+        ));
+        assert_eq!(
+            Message::decode(&mut bytes, Network::Testnet4).unwrap(),
+            message
+        );
+    }
+
+    #[test]
+    fn decode_checksum_mismatch() {
+        let mut bytes = BytesMut::new();
+        Message::new(Network::Mainnet, Command::Version, version_payload()).encode(&mut bytes);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(matches!(
+            Message::decode(&mut bytes, Network::Mainnet),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn decode_trailing_payload_bytes() {
+        let mut payload = BytesMut::new();
+        version_payload().encode(&mut payload);
+        payload.put_u8(0);
+        let mut bytes = BytesMut::new();
+        Network::Mainnet.magic().encode(&mut bytes);
+        Command::Version.encode(&mut bytes);
+        (payload.len() as u32).encode(&mut bytes);
+        payload.sha256().encode(&mut bytes);
+        bytes.extend_from_slice(&payload);
+        assert!(matches!(
+            Message::decode(&mut bytes, Network::Mainnet),
+            Err(Error::PayloadLength {
+                expected: 103,
+                actual: 102
+            })
+        ));
+    }
+
+    #[test]
+    fn decode_version_without_relay() {
+        let Payload::Version(mut version) = version_payload() else {
+            unreachable!()
+        };
+        version.version = 60002;
+        version.relay = None;
+        let mut bytes = BytesMut::new();
+        assert_eq!(version.encode(&mut bytes), 101);
+        assert_eq!(VersionMessage::decode(&mut bytes).unwrap(), version);
+    }
+
+    fn version_payload() -> Payload {
+        Payload::Version(VersionMessage {
+            version: 70016,
+            services: 1033.into(),
+            timestamp: 1680126222,
+            addr_recv: Address {
+                time: (),
+                services: 0.into(),
+                ip: "2a02:8308:900c:5900:b59b:b551:1c26:2a8".parse().unwrap(),
+                port: Port(56190),
+            },
+            addr_from: Address {
+                time: (),
+                services: 1033.into(),
+                ip: "::".parse().unwrap(),
+                port: Port(0),
+            },
+            nonce: 6940951773072803923,
+            user_agent: "/Satoshi:23.0.0/".into(),
+            start_height: 783080,
+            relay: Some(true),
+        })
+    }
+
+    #[test]
+    fn unknown_command_roundtrip() {
+        // feefilter followed by a verack, the second frame must stay intact
+        let message_bin = b"\xf9\xbe\xb4\xd9feefilter\0\0\0\x08\0\0\0\xe8\x0f\xd1\x9f\xe8\x03\0\0\0\0\0\0\xf9\xbe\xb4\xd9verack\0\0\0\0\0\0\0\0\0\0]\xf6\xe0\xe2";
+        let mut bytes = BytesMut::from(&message_bin[..]);
+
+        let message = Message::decode(&mut bytes, Network::Mainnet).unwrap();
+        assert_eq!(message.command().to_string(), "feefilter");
+        assert_eq!(
+            message.payload(),
+            &Payload::Raw(Bytes::from_static(b"\xe8\x03\0\0\0\0\0\0"))
+        );
+
+        let mut encoded = BytesMut::new();
+        message.encode(&mut encoded);
+        assert_eq!(&encoded[..], &message_bin[..32]);
+
+        let verack = Message::decode(&mut bytes, Network::Mainnet).unwrap();
+        assert_eq!(verack.payload(), &Payload::VerAck);
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn ping_pong_roundtrip() {
+        for (command, payload) in [
+            (Command::Ping, Payload::Ping(0x0123456789abcdef)),
+            (Command::Pong, Payload::Pong(0x0123456789abcdef)),
+        ] {
+            let message = Message::new(Network::Mainnet, command, payload);
+            let mut buffer = BytesMut::new();
+            assert_eq!(message.encode(&mut buffer), 32);
+            assert_eq!(
+                Message::decode(&mut buffer, Network::Mainnet).unwrap(),
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn decode_addr() {
+        let payload_bin = b"\x01\xe2\x15\x10\x4d\x01\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\xff\xff\x0a\0\0\x01\x20\x8d";
+        let mut bytes = BytesMut::from(&payload_bin[..]);
+        let payload = Payload::decode_command(&Command::Addr, &mut bytes).unwrap();
+        assert_eq!(
+            payload,
+            Payload::Addr(vec![Address {
+                time: 1292899810,
+                services: 1.into(),
+                ip: "::ffff:10.0.0.1".parse().unwrap(),
+                port: Port(8333),
+            }])
+        );
+        assert!(bytes.is_empty());
+
+        let mut encoded = BytesMut::new();
+        payload.encode(&mut encoded);
+        assert_eq!(&encoded[..], &payload_bin[..]);
+    }
+
+    #[test]
+    fn addr_count_limit() {
+        let mut bytes = BytesMut::new();
+        VariableInt(MAX_ADDR_TO_SEND as u64 + 1).encode(&mut bytes);
+        assert!(matches!(
+            Payload::decode_command(&Command::AddrV2, &mut bytes),
+            Err(Error::LengthTooLarge { what: "addrv2", .. })
+        ));
+    }
+
+    #[test]
+    fn variable_int_widths() {
+        for (value, encoded) in [
+            (0xFC, &b"\xfc"[..]),
+            (0xFD, b"\xfd\xfd\x00"),
+            (0x1_0000, b"\xfe\x00\x00\x01\x00"),
+            (0x1_0000_0000, b"\xff\x00\x00\x00\x00\x01\x00\x00\x00"),
+        ] {
+            let mut bytes = BytesMut::new();
+            assert_eq!(VariableInt(value).encode(&mut bytes), encoded.len());
+            assert_eq!(&bytes[..], encoded);
+            assert_eq!(VariableInt::decode(&mut bytes).unwrap(), VariableInt(value));
+        }
+    }
+
+    #[test]
+    fn collections_roundtrip() {
+        let value = (
+            vec![1u16, 2, 3],
+            *b"\xf9\xbe\xb4\xd9",
+            Bytes::from_static(b"abc"),
+            Some(7u32),
+        );
+        let mut bytes = BytesMut::new();
+        assert_eq!(value.encode(&mut bytes), 7 + 4 + 4 + 4);
+        assert_eq!(&bytes[..8], b"\x03\x01\x00\x02\x00\x03\x00\xf9");
+        let decoded: (Vec<u16>, [u8; 4], Bytes, Option<u32>) = Decode::decode(&mut bytes).unwrap();
+        assert_eq!(decoded, value);
+
+        // A trailing field the peer left out
+        let mut bytes = BytesMut::from(&b"\x01\x00"[..]);
+        assert_eq!(<(u16, Option<u32>)>::decode(&mut bytes).unwrap(), (1, None));
+
+        let mut bytes = BytesMut::new();
+        VariableInt(MAX_SIZE as u64 + 1).encode(&mut bytes);
+        assert!(matches!(
+            Vec::<u8>::decode(&mut bytes.clone()),
+            Err(Error::LengthTooLarge { what: "vector", .. })
+        ));
+        assert!(matches!(
+            Bytes::decode(&mut bytes),
+            Err(Error::LengthTooLarge { .. })
+        ));
+
+        // A huge count backed by nothing fails without reserving for it
+        let mut bytes = BytesMut::new();
+        VariableInt(MAX_SIZE as u64).encode(&mut bytes);
+        assert!(matches!(
+            Vec::<crate::p2p::bitcoin::hashes::Hash256>::decode(&mut bytes),
+            Err(Error::NotEnoughBytes("hash"))
+        ));
+    }
+
+    #[test]
+    fn inventory_roundtrip() {
+        use crate::p2p::bitcoin::{hashes::Hash256, inventory::InvType};
+
+        let inventory = vec![
+            InvVector {
+                inv_type: InvType::WitnessTx,
+                hash: Hash256([1; 32]),
+            },
+            InvVector {
+                inv_type: InvType::Block,
+                hash: Hash256([2; 32]),
+            },
+        ];
+        for (command, payload) in [
+            (Command::Inv, Payload::Inv(inventory.clone())),
+            (Command::GetData, Payload::GetData(inventory.clone())),
+            (Command::NotFound, Payload::NotFound(inventory)),
+        ] {
+            let message = Message::new(Network::Mainnet, command, payload);
+            let mut buffer = BytesMut::new();
+            assert_eq!(message.encode(&mut buffer), 24 + 1 + 2 * 36);
+            assert_eq!(
+                Message::decode(&mut buffer, Network::Mainnet).unwrap(),
+                message
+            );
+        }
+
+        let mut bytes = BytesMut::new();
+        VariableInt(MAX_INV_SZ as u64 + 1).encode(&mut bytes);
+        assert!(matches!(
+            Payload::decode_command(&Command::Inv, &mut bytes),
+            Err(Error::LengthTooLarge { what: "inv", .. })
+        ));
+    }
+
+    #[test]
+    fn headers_roundtrip() {
+        use crate::p2p::bitcoin::hashes::Hash256;
+
+        let header = BlockHeader {
+            version: 0x20000000,
+            prev_blockhash: Hash256([3; 32]),
+            merkle_root: Hash256([4; 32]),
+            time: 1_700_000_000,
+            bits: 0x17034219,
+            nonce: 42,
+        };
+        let getheaders = GetHeadersMessage {
+            version: 70016,
+            locator: vec![header.hash(), Network::Mainnet.genesis_hash()],
+            stop_hash: Hash256::ZERO,
+        };
+        for (command, payload, length) in [
+            (
+                Command::Headers,
+                Payload::Headers(vec![header; 2]),
+                1 + 2 * 81,
+            ),
+            (
+                Command::GetHeaders,
+                Payload::GetHeaders(getheaders),
+                4 + 1 + 3 * 32,
+            ),
+        ] {
+            let message = Message::new(Network::Mainnet, command, payload);
+            let mut buffer = BytesMut::new();
+            assert_eq!(message.encode(&mut buffer), 24 + length);
+            assert_eq!(
+                Message::decode(&mut buffer, Network::Mainnet).unwrap(),
+                message
+            );
+        }
+    }
+
     #[test]
     fn test_payload_length() {
         let payload = Payload::Version(VersionMessage {
             version: 70016,
-            services: 1033,
+            services: 1033.into(),
             timestamp: 1680126222,
             addr_recv: Address {
                 time: (),
-                services: 0,
+                services: 0.into(),
                 ip: "2a02:8308:900c:5900:b59b:b551:1c26:2a8".parse().unwrap(),
                 port: Port(56190),
             },
             addr_from: Address {
                 time: (),
-                services: 1033,
+                services: 1033.into(),
                 ip: "::192.168.0.1".parse().unwrap(),
                 port: Port(0),
             },
             nonce: 6920951773072803923,
             user_agent: "/Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto//Satoshi:23.0.0:Nakamoto/".into(),
             start_height: 1932515342,
-            relay: true,
+            relay: Some(true),
         });
         assert_eq!(payload.encode(&mut BytesMut::new()), 813);
     }
+
+    #[test]
+    fn derived_wire_attributes() {
+        #[derive(Debug, Clone, Eq, PartialEq, Encode, Decode)]
+        struct Sample {
+            version: u32,
+            #[wire(big_endian)]
+            port: u16,
+            #[wire(var_int, max = 2)]
+            items: Vec<u32>,
+            magic: [u8; 4],
+            pair: [u16; 2],
+            #[wire(since(version = 2))]
+            extra: u64,
+        }
+
+        let sample = Sample {
+            version: 2,
+            port: 8333,
+            items: vec![1, 2],
+            magic: *b"\xf9\xbe\xb4\xd9",
+            pair: [3, 4],
+            extra: 5,
+        };
+        let mut buffer = BytesMut::new();
+        assert_eq!(sample.encode(&mut buffer), 4 + 2 + 9 + 4 + 4 + 8);
+        assert_eq!(&buffer[4..6], b"\x20\x8d");
+        assert_eq!(Sample::decode(&mut buffer.clone()).unwrap(), sample);
+
+        let old = Sample {
+            version: 1,
+            extra: 0,
+            ..sample.clone()
+        };
+        let mut buffer = BytesMut::new();
+        assert_eq!(old.encode(&mut buffer), 4 + 2 + 9 + 4 + 4);
+        assert_eq!(Sample::decode(&mut buffer).unwrap(), old);
+
+        let too_many = Sample {
+            items: vec![1, 2, 3],
+            ..sample
+        };
+        let mut buffer = BytesMut::new();
+        too_many.encode(&mut buffer);
+        assert!(Sample::decode(&mut buffer).is_err());
+        assert!(matches!(
+            Sample::decode(&mut BytesMut::from(&b"\x02\0\0\0\x20"[..])),
+            Err(Error::NotEnoughBytes("Sample.port"))
+        ));
+    }
 }
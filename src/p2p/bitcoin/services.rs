@@ -0,0 +1,188 @@
+use super::{Decode, Encode, Result};
+use bytes::BytesMut;
+use std::{fmt, ops};
+
+/// Service bits advertised in `version` and address messages.
+///
+/// Bits without a name here are kept as they are, so relaying an address never drops services
+/// we don't know about.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct ServiceFlags(u64);
+
+/// No services, the usual value for light clients.
+pub const NODE_NONE: ServiceFlags = ServiceFlags(0);
+/// Serves the full block chain.
+pub const NODE_NETWORK: ServiceFlags = ServiceFlags(1);
+/// Accepts bloom filters (BIP111).
+pub const NODE_BLOOM: ServiceFlags = ServiceFlags(1 << 2);
+/// Serves blocks and transactions with witnesses (BIP144).
+pub const NODE_WITNESS: ServiceFlags = ServiceFlags(1 << 3);
+/// Serves compact block filters (BIP157).
+pub const NODE_COMPACT_FILTERS: ServiceFlags = ServiceFlags(1 << 6);
+/// Serves the last 288 blocks (BIP159).
+pub const NODE_NETWORK_LIMITED: ServiceFlags = ServiceFlags(1 << 10);
+/// Accepts the encrypted v2 transport (BIP324).
+pub const NODE_P2P_V2: ServiceFlags = ServiceFlags(1 << 11);
+
+const NAMES: [(ServiceFlags, &str); 6] = [
+    (NODE_NETWORK, "NODE_NETWORK"),
+    (NODE_BLOOM, "NODE_BLOOM"),
+    (NODE_WITNESS, "NODE_WITNESS"),
+    (NODE_COMPACT_FILTERS, "NODE_COMPACT_FILTERS"),
+    (NODE_NETWORK_LIMITED, "NODE_NETWORK_LIMITED"),
+    (NODE_P2P_V2, "NODE_P2P_V2"),
+];
+
+impl ServiceFlags {
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether every bit of `other` is set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Self) {
+        self.0 &= !other.0;
+    }
+
+    /// Bits not covered by any of the named constants.
+    pub fn unknown(self) -> u64 {
+        NAMES.iter().fold(self.0, |bits, (flag, _)| bits & !flag.0)
+    }
+}
+
+impl From<u64> for ServiceFlags {
+    fn from(bits: u64) -> Self {
+        Self(bits)
+    }
+}
+
+impl From<ServiceFlags> for u64 {
+    fn from(services: ServiceFlags) -> Self {
+        services.0
+    }
+}
+
+impl ops::BitOr for ServiceFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl ops::BitOrAssign for ServiceFlags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.insert(rhs);
+    }
+}
+
+impl ops::BitAnd for ServiceFlags {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl ops::BitAndAssign for ServiceFlags {
+    fn bitand_assign(&mut self, rhs: Self) {
+        self.0 &= rhs.0;
+    }
+}
+
+impl ops::Sub for ServiceFlags {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self(self.0 & !rhs.0)
+    }
+}
+
+impl ops::SubAssign for ServiceFlags {
+    fn sub_assign(&mut self, rhs: Self) {
+        self.remove(rhs);
+    }
+}
+
+/// Lists the set names separated by `|`, unknown bits are shown as one hex number.
+impl fmt::Display for ServiceFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("NODE_NONE");
+        }
+        let mut separator = "";
+        for (flag, name) in NAMES {
+            if self.contains(flag) {
+                write!(f, "{separator}{name}")?;
+                separator = "|";
+            }
+        }
+        let unknown = self.unknown();
+        if unknown != 0 {
+            write!(f, "{separator}{unknown:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Encode for ServiceFlags {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        self.0.encode(buffer)
+    }
+}
+
+impl Decode for ServiceFlags {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        u64::decode(bytes).map(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_operations() {
+        let mut services = NODE_NETWORK | NODE_WITNESS;
+        assert!(services.contains(NODE_WITNESS));
+        assert!(!services.contains(NODE_WITNESS | NODE_BLOOM));
+        assert!(services.contains(NODE_NONE));
+
+        services |= NODE_P2P_V2;
+        services -= NODE_NETWORK;
+        assert_eq!(services, NODE_WITNESS | NODE_P2P_V2);
+        assert_eq!(services & NODE_P2P_V2, NODE_P2P_V2);
+        assert_eq!(services.bits(), 0x808);
+    }
+
+    #[test]
+    fn display_keeps_unknown_bits() {
+        assert_eq!(NODE_NONE.to_string(), "NODE_NONE");
+        assert_eq!(
+            ServiceFlags::from(1033).to_string(),
+            "NODE_NETWORK|NODE_WITNESS|NODE_NETWORK_LIMITED"
+        );
+        let services = ServiceFlags::from(NODE_NETWORK.bits() | 1 << 24 | 1 << 40);
+        assert_eq!(services.unknown(), 1 << 24 | 1 << 40);
+        assert_eq!(services.to_string(), "NODE_NETWORK|0x10001000000");
+
+        let mut buffer = BytesMut::new();
+        services.encode(&mut buffer);
+        assert_eq!(ServiceFlags::decode(&mut buffer).unwrap(), services);
+    }
+}
@@ -0,0 +1,244 @@
+//! Headers-first synchronisation on top of connections made by [`Handshake`](super::Handshake).
+
+use super::{
+    block::{BlockHeader, GetHeadersMessage, MAX_HEADERS_RESULTS},
+    chain::{Connected, HeaderChain},
+    config::PROTOCOL_VERSION,
+    hashes::Hash256,
+    inventory::InvType,
+    network::Network,
+    protocol::{Command, Message, Payload},
+    Error,
+};
+use futures::{
+    stream::{self, AbortHandle},
+    StreamExt,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+
+/// What [`HeaderSync`] reports while it runs.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SyncEvent {
+    /// The best chain grew to `height`
+    Progress { height: u32, tip: Hash256 },
+    /// The best chain switched to a branch with more work
+    Reorg {
+        fork_height: u32,
+        disconnected: Vec<Hash256>,
+        connected: Vec<Hash256>,
+    },
+    /// A peer has no more headers for us, until it announces new blocks
+    PeerSynced { peer: usize, height: u32 },
+    /// A peer sent invalid headers and was disconnected
+    PeerMisbehaved { peer: usize, reason: String },
+}
+
+/// Drives `getheaders`/`headers` against any number of peers and keeps the best chain.
+pub struct HeaderSync {
+    chain: HeaderChain,
+    events: UnboundedSender<SyncEvent>,
+}
+
+impl HeaderSync {
+    pub fn new(network: Network) -> (Self, UnboundedReceiver<SyncEvent>) {
+        Self::with_chain(HeaderChain::new(network))
+    }
+
+    /// Resumes from an existing chain, for example one with custom checkpoints.
+    pub fn with_chain(chain: HeaderChain) -> (Self, UnboundedReceiver<SyncEvent>) {
+        let (events, events_rx) = mpsc::unbounded_channel();
+        (Self { chain, events }, events_rx)
+    }
+
+    pub fn chain(&self) -> &HeaderChain {
+        &self.chain
+    }
+
+    /// Syncs from the given peers, the sender and receiver halves of
+    /// [`Handshake::split`](super::Handshake::split), and keeps following their announcements
+    /// until every one of them is gone.
+    pub async fn run(&mut self, peers: Vec<(Sender<Message>, Receiver<Message>)>) {
+        let network = self.chain.network();
+        let mut senders = Vec::with_capacity(peers.len());
+        let mut receivers = Vec::with_capacity(peers.len());
+        let mut disconnects: Vec<AbortHandle> = Vec::with_capacity(peers.len());
+        for (peer, (tx, rx)) in peers.into_iter().enumerate() {
+            let requests = [
+                Message::new(network, Command::SendHeaders, Payload::SendHeaders),
+                self.getheaders(),
+            ];
+            let mut connected = true;
+            for message in requests {
+                connected &= tx.send(message).await.is_ok();
+            }
+            senders.push(connected.then_some(tx));
+            // Aborting drops the receiver, which makes the handshake close the connection
+            let (messages, disconnect) =
+                stream::abortable(stream::unfold(rx, |mut rx| async move {
+                    rx.recv().await.map(|message| (message, rx))
+                }));
+            receivers.push(messages.map(move |message| (peer, message)).boxed());
+            disconnects.push(disconnect);
+        }
+        let mut synced = vec![false; senders.len()];
+
+        let mut messages = stream::select_all(receivers);
+        while let Some((peer, message)) = messages.next().await {
+            let Some(tx) = senders[peer].clone() else {
+                continue;
+            };
+            let request = match message.payload() {
+                Payload::Headers(headers) => match self.connect(headers) {
+                    Ok(()) if headers.len() == MAX_HEADERS_RESULTS => true,
+                    Ok(()) => {
+                        if !synced[peer] {
+                            synced[peer] = true;
+                            let height = self.chain.height();
+                            let _ = self.events.send(SyncEvent::PeerSynced { peer, height });
+                        }
+                        false
+                    }
+                    // An announcement of a block whose parent we don't have yet
+                    Err(Error::UnconnectedHeader { .. }) if headers.len() < MAX_HEADERS_RESULTS => {
+                        true
+                    }
+                    Err(e) => {
+                        tracing::warn!("Disconnecting peer {peer}: {e}");
+                        senders[peer] = None;
+                        disconnects[peer].abort();
+                        let reason = e.to_string();
+                        let _ = self.events.send(SyncEvent::PeerMisbehaved { peer, reason });
+                        continue;
+                    }
+                },
+                Payload::Inv(inventory) => inventory.iter().any(|inv| {
+                    matches!(inv.inv_type, InvType::Block | InvType::WitnessBlock)
+                        && self.chain.get(&inv.hash).is_none()
+                }),
+                _ => false,
+            };
+            if request && tx.send(self.getheaders()).await.is_err() {
+                senders[peer] = None;
+            }
+        }
+    }
+
+    fn getheaders(&self) -> Message {
+        let getheaders = GetHeadersMessage {
+            version: PROTOCOL_VERSION as u32,
+            locator: self.chain.locator(),
+            stop_hash: Hash256::ZERO,
+        };
+        Message::new(
+            self.chain.network(),
+            Command::GetHeaders,
+            Payload::GetHeaders(getheaders),
+        )
+    }
+
+    fn connect(&mut self, headers: &[BlockHeader]) -> Result<(), Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() as u32)
+            .unwrap_or_default();
+        let start = self.chain.tip().hash;
+        for header in headers {
+            match self.chain.connect(*header, now)? {
+                Connected::Reorg {
+                    fork_height,
+                    disconnected,
+                    connected,
+                } => {
+                    let _ = self.events.send(SyncEvent::Reorg {
+                        fork_height,
+                        disconnected,
+                        connected,
+                    });
+                }
+                Connected::Duplicate | Connected::Extended { .. } | Connected::SideChain { .. } => {
+                }
+            }
+        }
+        let tip = self.chain.tip();
+        if tip.hash != start {
+            let _ = self.events.send(SyncEvent::Progress {
+                height: tip.height,
+                tip: tip.hash,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::bitcoin::chain::tests::extend;
+
+    #[tokio::test]
+    async fn syncs_from_a_peer() {
+        let (mut sync, mut events) = HeaderSync::new(Network::Regtest);
+        let (to_sync, mut peer_rx) = mpsc::channel::<Message>(8);
+        let (peer_tx, from_peer) = mpsc::channel(8);
+
+        let headers = extend(&Network::Regtest.genesis_header(), 10, 1);
+        // Answers the first getheaders, then hangs up, which ends the sync
+        let peer = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            while let Some(message) = peer_rx.recv().await {
+                requests.push(message.command().clone());
+                let Payload::GetHeaders(request) = message.payload() else {
+                    continue;
+                };
+                assert_eq!(request.locator, [Network::Regtest.genesis_hash()]);
+                let reply = Payload::Headers(headers);
+                let message = Message::new(Network::Regtest, Command::Headers, reply);
+                peer_tx.send(message).await.unwrap();
+                break;
+            }
+            requests
+        });
+
+        sync.run(vec![(to_sync, from_peer)]).await;
+        assert_eq!(sync.chain().height(), 10);
+        assert_eq!(
+            peer.await.unwrap(),
+            [Command::SendHeaders, Command::GetHeaders]
+        );
+        assert!(matches!(
+            events.recv().await,
+            Some(SyncEvent::Progress { height: 10, .. })
+        ));
+        assert_eq!(
+            events.recv().await,
+            Some(SyncEvent::PeerSynced {
+                peer: 0,
+                height: 10
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn disconnects_a_misbehaving_peer() {
+        let (mut sync, mut events) = HeaderSync::new(Network::Regtest);
+        let (to_sync, _peer_rx) = mpsc::channel::<Message>(8);
+        let (peer_tx, from_peer) = mpsc::channel(8);
+
+        let mut bad_bits = extend(&Network::Regtest.genesis_header(), 1, 1)[0];
+        bad_bits.bits = 0x1d00ffff;
+        let reply = Payload::Headers(vec![bad_bits]);
+        peer_tx
+            .send(Message::new(Network::Regtest, Command::Headers, reply))
+            .await
+            .unwrap();
+
+        // The peer stays up, only dropping its receiver lets the sync end
+        sync.run(vec![(to_sync, from_peer)]).await;
+        assert!(peer_tx.is_closed());
+        assert!(matches!(
+            events.recv().await,
+            Some(SyncEvent::PeerMisbehaved { peer: 0, .. })
+        ));
+    }
+}
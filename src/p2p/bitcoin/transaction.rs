@@ -0,0 +1,268 @@
+//! Transactions, including the segregated witness serialization from
+//! [BIP144](https://github.com/bitcoin/bips/blob/master/bip-0144.mediawiki).
+
+use super::{
+    codec::MAX_PROTOCOL_MESSAGE_LENGTH,
+    decode::{decode_bytes, decode_list, MAX_SIZE},
+    encode::{encode_bytes, encode_list, VariableInt},
+    hashes::Hash256,
+    inventory::{InvType, InvVector},
+    Decode, Encode, Error, Result,
+};
+use bytes::{Buf, BytesMut};
+
+const MAX_SCRIPT_SIZE: usize = MAX_PROTOCOL_MESSAGE_LENGTH as usize;
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct OutPoint {
+    pub txid: Hash256,
+    pub vout: u32,
+}
+
+impl OutPoint {
+    /// Previous output of a coinbase input.
+    pub const NULL: Self = Self {
+        txid: Hash256::ZERO,
+        vout: u32::MAX,
+    };
+}
+
+impl Encode for OutPoint {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        self.txid.encode(buffer) + self.vout.encode(buffer)
+    }
+}
+
+impl Decode for OutPoint {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        let txid = Hash256::decode(bytes)?;
+        let vout = u32::decode(bytes)?;
+        Ok(OutPoint { txid, vout })
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct TxIn {
+    pub previous_output: OutPoint,
+    pub script_sig: Vec<u8>,
+    pub sequence: u32,
+    /// Witness stack, serialized separately after all outputs
+    pub witness: Vec<Vec<u8>>,
+}
+
+impl Encode for TxIn {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        let mut written = self.previous_output.encode(buffer);
+        written += encode_bytes(&self.script_sig, buffer);
+        written += self.sequence.encode(buffer);
+        written
+    }
+}
+
+impl Decode for TxIn {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        let previous_output = OutPoint::decode(bytes)?;
+        let script_sig = decode_bytes(bytes, "script sig", MAX_SCRIPT_SIZE)?;
+        let sequence = u32::decode(bytes)?;
+        Ok(TxIn {
+            previous_output,
+            script_sig,
+            sequence,
+            witness: Vec::new(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct TxOut {
+    /// Amount in satoshis
+    pub value: i64,
+    pub script_pubkey: Vec<u8>,
+}
+
+impl Encode for TxOut {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        self.value.encode(buffer) + encode_bytes(&self.script_pubkey, buffer)
+    }
+}
+
+impl Decode for TxOut {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        let value = i64::decode(bytes)?;
+        let script_pubkey = decode_bytes(bytes, "script pubkey", MAX_SCRIPT_SIZE)?;
+        Ok(TxOut {
+            value,
+            script_pubkey,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct Transaction {
+    pub version: i32,
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<TxOut>,
+    pub lock_time: u32,
+}
+
+impl Transaction {
+    pub fn has_witness(&self) -> bool {
+        self.inputs.iter().any(|input| !input.witness.is_empty())
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].previous_output == OutPoint::NULL
+    }
+
+    /// Hash of the serialization without witness data.
+    pub fn txid(&self) -> Hash256 {
+        let mut buffer = BytesMut::new();
+        self.encode_legacy(&mut buffer);
+        Hash256::hash(&buffer)
+    }
+
+    /// Hash of the full serialization, equal to [`Transaction::txid`] without witnesses.
+    pub fn wtxid(&self) -> Hash256 {
+        let mut buffer = BytesMut::new();
+        self.encode(&mut buffer);
+        Hash256::hash(&buffer)
+    }
+
+    /// Inventory entry announcing this transaction, by wtxid once wtxidrelay is negotiated.
+    pub fn inv(&self, wtxid_relay: bool) -> InvVector {
+        if wtxid_relay {
+            InvVector {
+                inv_type: InvType::Wtx,
+                hash: self.wtxid(),
+            }
+        } else {
+            InvVector {
+                inv_type: InvType::Tx,
+                hash: self.txid(),
+            }
+        }
+    }
+
+    fn encode_legacy(&self, buffer: &mut BytesMut) -> usize {
+        let mut written = self.version.encode(buffer);
+        written += encode_list(&self.inputs, buffer);
+        written += encode_list(&self.outputs, buffer);
+        written += self.lock_time.encode(buffer);
+        written
+    }
+}
+
+impl Encode for Transaction {
+    fn encode(&self, buffer: &mut BytesMut) -> usize {
+        if !self.has_witness() {
+            return self.encode_legacy(buffer);
+        }
+        let mut written = self.version.encode(buffer);
+        // Marker and flag
+        written += 0_u8.encode(buffer);
+        written += 1_u8.encode(buffer);
+        written += encode_list(&self.inputs, buffer);
+        written += encode_list(&self.outputs, buffer);
+        for input in &self.inputs {
+            written += VariableInt(input.witness.len() as u64).encode(buffer);
+            for item in &input.witness {
+                written += encode_bytes(item, buffer);
+            }
+        }
+        written += self.lock_time.encode(buffer);
+        written
+    }
+}
+
+impl Decode for Transaction {
+    fn decode(bytes: &mut BytesMut) -> Result<Self> {
+        let version = i32::decode(bytes)?;
+        // An empty input list can only mean the segwit marker follows
+        let segwit = bytes.first() == Some(&0);
+        if segwit {
+            bytes.advance(1);
+            if u8::decode(bytes)? != 1 {
+                return Err(Error::InvalidTransaction("unknown segwit flag"));
+            }
+        }
+        let mut inputs: Vec<TxIn> = decode_list(bytes, "transaction inputs", MAX_SIZE)?;
+        let outputs = decode_list(bytes, "transaction outputs", MAX_SIZE)?;
+        if segwit {
+            for input in &mut inputs {
+                let count = VariableInt::decode_length(bytes, "witness stack", MAX_SIZE)?;
+                input.witness = (0..count)
+                    .map(|_| decode_bytes(bytes, "witness item", MAX_SCRIPT_SIZE))
+                    .collect::<Result<_>>()?;
+            }
+            if inputs.iter().all(|input| input.witness.is_empty()) {
+                return Err(Error::InvalidTransaction("superfluous witness record"));
+            }
+        }
+        let lock_time = u32::decode(bytes)?;
+        Ok(Transaction {
+            version,
+            inputs,
+            outputs,
+            lock_time,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn decode_hex(tx: &str) -> Transaction {
+        let mut bytes = BytesMut::from(&hex::decode(tx).unwrap()[..]);
+        let transaction = Transaction::decode(&mut bytes).unwrap();
+        assert!(bytes.is_empty());
+        transaction
+    }
+
+    #[test]
+    fn legacy_transaction() {
+        // Coinbase of the genesis block
+        let tx = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+        let transaction = decode_hex(tx);
+        assert!(transaction.is_coinbase());
+        assert!(!transaction.has_witness());
+        assert_eq!(transaction.outputs[0].value, 50_0000_0000);
+        assert_eq!(
+            transaction.txid().to_string(),
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"
+        );
+        assert_eq!(transaction.wtxid(), transaction.txid());
+
+        let mut buffer = BytesMut::new();
+        transaction.encode(&mut buffer);
+        assert_eq!(hex::encode(buffer), tx);
+    }
+
+    #[test]
+    fn segwit_transaction() {
+        // Native P2WPKH spend from BIP143
+        let tx = "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f00000000494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000";
+        let transaction = decode_hex(tx);
+        assert!(transaction.has_witness());
+        assert!(transaction.inputs[0].witness.is_empty());
+        assert_eq!(transaction.inputs[1].witness.len(), 2);
+        assert_ne!(transaction.wtxid(), transaction.txid());
+        assert_eq!(transaction.inv(false).hash, transaction.txid());
+        assert_eq!(transaction.inv(true).inv_type, InvType::Wtx);
+
+        let mut buffer = BytesMut::new();
+        transaction.encode(&mut buffer);
+        assert_eq!(hex::encode(buffer), tx);
+    }
+
+    #[test]
+    fn superfluous_witness() {
+        let tx = "010000000001010000000000000000000000000000000000000000000000000000000000000000ffffffff00ffffffff0000000000000000";
+        let mut bytes = BytesMut::from(&hex::decode(tx).unwrap()[..]);
+        assert!(matches!(
+            Transaction::decode(&mut bytes),
+            Err(Error::InvalidTransaction(_))
+        ));
+    }
+}
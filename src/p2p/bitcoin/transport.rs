@@ -0,0 +1,697 @@
+//! Encrypted v2 transport from [BIP324](https://github.com/bitcoin/bips/blob/master/bip-0324.mediawiki).
+//!
+//! Both sides send an ElligatorSwift encoded key followed by random garbage, derive the session
+//! keys from the x-only ECDH secret and then exchange ChaCha20-Poly1305 packets. Nothing on the
+//! wire is distinguishable from random bytes.
+
+use super::{
+    codec::Limits,
+    network::Network,
+    protocol::{Command, Message},
+    Encode, Error, Result,
+};
+use bytes::{Buf, BufMut, BytesMut};
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
+    ChaCha20,
+};
+use chacha20poly1305::{aead::AeadInPlace, ChaCha20Poly1305, KeyInit, Tag};
+use hkdf::Hkdf;
+use rand::Rng;
+use secp256k1::{
+    ellswift::{ElligatorSwift, ElligatorSwiftParty},
+    Secp256k1, SecretKey,
+};
+use sha2::Sha256;
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Packets (or length chunks) encrypted with a key before it is replaced.
+const REKEY_INTERVAL: u32 = 224;
+
+const ELLSWIFT_LENGTH: usize = 64;
+const MAX_GARBAGE_LENGTH: usize = 4095;
+const GARBAGE_TERMINATOR_LENGTH: usize = 16;
+const LENGTH_FIELD_LENGTH: usize = 3;
+const HEADER_LENGTH: usize = 1;
+const TAG_LENGTH: usize = 16;
+
+/// Header bit of decoy packets, which the receiver drops.
+const IGNORE_BIT: u8 = 0x80;
+
+/// Bytes of a v1 `version` header the responder compares against: magic and command.
+const V1_PREFIX_LENGTH: usize = 16;
+
+/// Message types with a one byte encoding, the short id is the position plus one.
+const SHORT_IDS: [&str; 28] = [
+    "addr",
+    "block",
+    "blocktxn",
+    "cmpctblock",
+    "feefilter",
+    "filteradd",
+    "filterclear",
+    "filterload",
+    "getblocks",
+    "getblocktxn",
+    "getdata",
+    "getheaders",
+    "headers",
+    "inv",
+    "mempool",
+    "merkleblock",
+    "notfound",
+    "ping",
+    "pong",
+    "sendcmpct",
+    "tx",
+    "getcfilters",
+    "cfilter",
+    "getcfheaders",
+    "cfheaders",
+    "getcfcheckpt",
+    "cfcheckpt",
+    "addrv2",
+];
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(super) enum Role {
+    Initiator,
+    Responder,
+}
+
+fn nonce(first: u32, second: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[..4].copy_from_slice(&first.to_le_bytes());
+    nonce[4..].copy_from_slice(&second.to_le_bytes());
+    nonce
+}
+
+/// ChaCha20 stream for the length fields, rekeyed from its own keystream every
+/// [`REKEY_INTERVAL`] chunks.
+///
+/// Only the stream position is kept, the cipher itself is cheap to set up again.
+#[derive(Clone)]
+struct FSChaCha20 {
+    key: [u8; 32],
+    position: u64,
+    chunk_counter: u32,
+    rekey_counter: u64,
+}
+
+impl FSChaCha20 {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            position: 0,
+            chunk_counter: 0,
+            rekey_counter: 0,
+        }
+    }
+
+    fn crypt(&mut self, chunk: &mut [u8]) {
+        let mut cipher = ChaCha20::new(&self.key.into(), &nonce(0, self.rekey_counter).into());
+        cipher.seek(self.position);
+        cipher.apply_keystream(chunk);
+        self.position += chunk.len() as u64;
+        self.chunk_counter += 1;
+        if self.chunk_counter == REKEY_INTERVAL {
+            let mut key = [0; 32];
+            cipher.apply_keystream(&mut key);
+            self.key = key;
+            self.position = 0;
+            self.chunk_counter = 0;
+            self.rekey_counter += 1;
+        }
+    }
+}
+
+/// ChaCha20-Poly1305 for the packets, with the packet counter as nonce and a new key every
+/// [`REKEY_INTERVAL`] packets.
+#[derive(Clone)]
+struct FSChaCha20Poly1305 {
+    key: [u8; 32],
+    packet_counter: u32,
+    rekey_counter: u64,
+}
+
+impl FSChaCha20Poly1305 {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            packet_counter: 0,
+            rekey_counter: 0,
+        }
+    }
+
+    fn encrypt(&mut self, aad: &[u8], buffer: &mut [u8]) -> Tag {
+        let nonce = nonce(self.packet_counter, self.rekey_counter);
+        let tag = ChaCha20Poly1305::new(&self.key.into())
+            .encrypt_in_place_detached(&nonce.into(), aad, buffer)
+            .expect("packets are far below the ChaCha20 length limit");
+        self.next_packet();
+        tag
+    }
+
+    fn decrypt(&mut self, aad: &[u8], buffer: &mut [u8], tag: &[u8]) -> Result<()> {
+        let nonce = nonce(self.packet_counter, self.rekey_counter);
+        ChaCha20Poly1305::new(&self.key.into())
+            .decrypt_in_place_detached(&nonce.into(), aad, buffer, Tag::from_slice(tag))
+            .map_err(|_| Error::V2Transport("packet authentication failed"))?;
+        self.next_packet();
+        Ok(())
+    }
+
+    fn next_packet(&mut self) {
+        self.packet_counter += 1;
+        if self.packet_counter == REKEY_INTERVAL {
+            // The new key is the keystream right after the block used for the Poly1305 key
+            let mut cipher = ChaCha20::new(
+                &self.key.into(),
+                &nonce(u32::MAX, self.rekey_counter).into(),
+            );
+            cipher.seek(64);
+            let mut key = [0; 32];
+            cipher.apply_keystream(&mut key);
+            self.key = key;
+            self.packet_counter = 0;
+            self.rekey_counter += 1;
+        }
+    }
+}
+
+/// Ciphers for one direction of a connection.
+#[derive(Clone)]
+struct PacketCipher {
+    length: FSChaCha20,
+    aead: FSChaCha20Poly1305,
+}
+
+impl PacketCipher {
+    fn encrypt(&mut self, contents: &[u8], aad: &[u8], ignore: bool, dst: &mut BytesMut) {
+        let mut length = (contents.len() as u32).to_le_bytes();
+        self.length.crypt(&mut length[..LENGTH_FIELD_LENGTH]);
+        dst.put_slice(&length[..LENGTH_FIELD_LENGTH]);
+
+        let mut plaintext = Vec::with_capacity(HEADER_LENGTH + contents.len());
+        plaintext.push(if ignore { IGNORE_BIT } else { 0 });
+        plaintext.extend_from_slice(contents);
+        let tag = self.aead.encrypt(aad, &mut plaintext);
+        dst.put_slice(&plaintext);
+        dst.put_slice(&tag);
+    }
+}
+
+/// Session keys and garbage terminators derived from the ECDH secret.
+struct Keys {
+    send: PacketCipher,
+    recv: PacketCipher,
+    send_terminator: [u8; GARBAGE_TERMINATOR_LENGTH],
+    recv_terminator: [u8; GARBAGE_TERMINATOR_LENGTH],
+    session_id: [u8; 32],
+}
+
+impl Keys {
+    fn derive(secret: &[u8; 32], network: Network, role: Role) -> Self {
+        let salt = [
+            b"bitcoin_v2_shared_secret".as_slice(),
+            &network.magic().to_le_bytes(),
+        ]
+        .concat();
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), secret);
+        let expand = |info: &str| {
+            let mut okm = [0; 32];
+            hkdf.expand(info.as_bytes(), &mut okm)
+                .expect("32 bytes is a valid HKDF-SHA256 output length");
+            okm
+        };
+        let cipher = |side: &str| PacketCipher {
+            length: FSChaCha20::new(expand(&format!("{side}_L"))),
+            aead: FSChaCha20Poly1305::new(expand(&format!("{side}_P"))),
+        };
+        let terminators = expand("garbage_terminators");
+        let mut initiator_terminator = [0; GARBAGE_TERMINATOR_LENGTH];
+        let mut responder_terminator = [0; GARBAGE_TERMINATOR_LENGTH];
+        initiator_terminator.copy_from_slice(&terminators[..GARBAGE_TERMINATOR_LENGTH]);
+        responder_terminator.copy_from_slice(&terminators[GARBAGE_TERMINATOR_LENGTH..]);
+
+        let (initiator, responder) = (cipher("initiator"), cipher("responder"));
+        let session_id = expand("session_id");
+        match role {
+            Role::Initiator => Keys {
+                send: initiator,
+                recv: responder,
+                send_terminator: initiator_terminator,
+                recv_terminator: responder_terminator,
+                session_id,
+            },
+            Role::Responder => Keys {
+                send: responder,
+                recv: initiator,
+                send_terminator: responder_terminator,
+                recv_terminator: initiator_terminator,
+                session_id,
+            },
+        }
+    }
+}
+
+/// State of an established v2 connection, driven by [`BitcoinCodec`](super::BitcoinCodec).
+#[derive(Clone)]
+pub struct V2Session {
+    send: PacketCipher,
+    recv: PacketCipher,
+    session_id: [u8; 32],
+    /// The peer's garbage, authenticated along with its first packet
+    recv_aad: Vec<u8>,
+    version_received: bool,
+    pending_length: Option<usize>,
+}
+
+impl fmt::Debug for V2Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("V2Session")
+            .field("session_id", &hex::encode(self.session_id))
+            .finish_non_exhaustive()
+    }
+}
+
+impl V2Session {
+    /// Identifies the session on both ends, peers can compare it out of band to rule out a
+    /// man in the middle.
+    pub fn session_id(&self) -> [u8; 32] {
+        self.session_id
+    }
+
+    pub(super) fn encode(&mut self, message: &Message, dst: &mut BytesMut) {
+        let mut contents = BytesMut::new();
+        let name = message.command().to_string();
+        match SHORT_IDS.iter().position(|short| *short == name) {
+            Some(index) => contents.put_u8(index as u8 + 1),
+            None => {
+                contents.put_u8(0);
+                message.command().encode(&mut contents);
+            }
+        }
+        message.payload().encode(&mut contents);
+        self.send.encrypt(&contents, &[], false, dst);
+    }
+
+    /// Sends a packet the peer drops, to obscure traffic patterns.
+    #[cfg(test)]
+    pub(super) fn encode_decoy(&mut self, contents: &[u8], dst: &mut BytesMut) {
+        self.send.encrypt(contents, &[], true, dst);
+    }
+
+    pub(super) fn decode(
+        &mut self,
+        src: &mut BytesMut,
+        network: Network,
+        limits: &Limits,
+    ) -> Result<Option<Message>> {
+        loop {
+            let length = match self.pending_length {
+                Some(length) => length,
+                None => {
+                    if src.len() < LENGTH_FIELD_LENGTH {
+                        return Ok(None);
+                    }
+                    let mut length = [0; 4];
+                    src.copy_to_slice(&mut length[..LENGTH_FIELD_LENGTH]);
+                    self.recv.length.crypt(&mut length[..LENGTH_FIELD_LENGTH]);
+                    let length = u32::from_le_bytes(length);
+                    // Short id or full command in front of the payload
+                    let limit = limits.largest() as usize + 1 + 12;
+                    if length as usize > limit {
+                        return Err(Error::LengthTooLarge {
+                            what: "v2 packet",
+                            length: length.into(),
+                            limit,
+                        });
+                    }
+                    self.pending_length = Some(length as usize);
+                    length as usize
+                }
+            };
+
+            let packet_length = HEADER_LENGTH + length + TAG_LENGTH;
+            if src.len() < packet_length {
+                src.reserve(packet_length - src.len());
+                return Ok(None);
+            }
+            self.pending_length = None;
+            let mut packet = src.split_to(packet_length);
+            let tag = packet.split_off(HEADER_LENGTH + length);
+            let aad = std::mem::take(&mut self.recv_aad);
+            self.recv.aead.decrypt(&aad, &mut packet, &tag)?;
+
+            let header = packet.get_u8();
+            if header & IGNORE_BIT != 0 {
+                continue;
+            }
+            // The version packet's contents are reserved for future extensions
+            if !self.version_received {
+                self.version_received = true;
+                continue;
+            }
+            if let Some(message) = decode_contents(packet, network, limits)? {
+                return Ok(Some(message));
+            }
+        }
+    }
+}
+
+fn decode_contents(
+    mut contents: BytesMut,
+    network: Network,
+    limits: &Limits,
+) -> Result<Option<Message>> {
+    if contents.is_empty() {
+        return Err(Error::V2Transport("packet without a message type"));
+    }
+    let command = match contents.get_u8() {
+        0 => {
+            if contents.len() < 12 {
+                return Err(Error::NotEnoughBytes("command"));
+            }
+            Command::from(<[u8; 12]>::try_from(&contents.split_to(12)[..])?)
+        }
+        id => match SHORT_IDS.get(id as usize - 1) {
+            Some(name) => {
+                let mut padded = [0; 12];
+                padded[..name.len()].copy_from_slice(name.as_bytes());
+                Command::from(padded)
+            }
+            None => {
+                tracing::debug!("Ignoring message with unknown short id {id}");
+                return Ok(None);
+            }
+        },
+    };
+    let limit = limits.limit(&command);
+    if contents.len() > limit as usize {
+        return Err(Error::MessageTooLarge {
+            command: command.to_string(),
+            length: contents.len() as u32,
+            limit,
+        });
+    }
+    Message::from_payload(network, command, contents).map(Some)
+}
+
+/// Reads the first bytes of an inbound connection and tells whether they start a v1
+/// `version` message. The bytes read are returned either way.
+pub(super) async fn sniff_v1<S>(stream: &mut S, network: Network) -> Result<(bool, BytesMut)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut prefix = BytesMut::with_capacity(V1_PREFIX_LENGTH);
+    network.magic().encode(&mut prefix);
+    Command::Version.encode(&mut prefix);
+
+    let mut received = BytesMut::new();
+    while received.len() < V1_PREFIX_LENGTH {
+        if stream.read_buf(&mut received).await? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let compared = received.len().min(V1_PREFIX_LENGTH);
+        if received[..compared] != prefix[..compared] {
+            return Ok((false, received));
+        }
+    }
+    Ok((true, received))
+}
+
+/// Runs the key exchange on `stream` and sends our version packet.
+///
+/// `received` holds whatever was already read from the peer. Returns the session along with
+/// bytes read past the garbage terminator, which belong to the first packets.
+pub(super) async fn handshake<S>(
+    stream: &mut S,
+    network: Network,
+    role: Role,
+    mut received: BytesMut,
+) -> Result<(V2Session, BytesMut)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let secret_key = SecretKey::new(&mut rand::thread_rng());
+    let secp = Secp256k1::new();
+    let ours = ElligatorSwift::from_seckey(&secp, secret_key, Some(rand::random()));
+    let garbage: Vec<u8> = {
+        let mut rng = rand::thread_rng();
+        let length = rng.gen_range(0..=MAX_GARBAGE_LENGTH);
+        (0..length).map(|_| rng.gen()).collect()
+    };
+    stream
+        .write_all(&[ours.to_array().as_slice(), &garbage].concat())
+        .await?;
+
+    while received.len() < ELLSWIFT_LENGTH {
+        if stream.read_buf(&mut received).await? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+    let mut theirs = [0; ELLSWIFT_LENGTH];
+    received.copy_to_slice(&mut theirs);
+    let theirs = ElligatorSwift::from_array(theirs);
+    let secret = match role {
+        Role::Initiator => {
+            ElligatorSwift::shared_secret(ours, theirs, secret_key, ElligatorSwiftParty::A, None)
+        }
+        Role::Responder => {
+            ElligatorSwift::shared_secret(theirs, ours, secret_key, ElligatorSwiftParty::B, None)
+        }
+    };
+    let mut keys = Keys::derive(secret.as_secret_bytes(), network, role);
+
+    let mut out = BytesMut::new();
+    out.put_slice(&keys.send_terminator);
+    keys.send.encrypt(&[], &garbage, false, &mut out);
+    stream.write_all(&out).await?;
+
+    let their_garbage = loop {
+        let found = received
+            .windows(GARBAGE_TERMINATOR_LENGTH)
+            .position(|window| window == keys.recv_terminator);
+        match found {
+            Some(position) if position <= MAX_GARBAGE_LENGTH => {
+                let garbage = received.split_to(position);
+                received.advance(GARBAGE_TERMINATOR_LENGTH);
+                break garbage;
+            }
+            Some(_) => return Err(Error::V2Transport("garbage too long")),
+            None if received.len() >= MAX_GARBAGE_LENGTH + GARBAGE_TERMINATOR_LENGTH => {
+                return Err(Error::V2Transport("garbage too long"))
+            }
+            None => {
+                if stream.read_buf(&mut received).await? == 0 {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+            }
+        }
+    };
+
+    let session = V2Session {
+        send: keys.send,
+        recv: keys.recv,
+        session_id: keys.session_id,
+        recv_aad: their_garbage.to_vec(),
+        version_received: false,
+        pending_length: None,
+    };
+    Ok((session, received))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::bitcoin::{codec::BitcoinCodec, protocol::Payload};
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::{Encoder, Framed, FramedParts};
+
+    fn framed<S>(stream: S, session: V2Session, leftover: BytesMut) -> Framed<S, BitcoinCodec>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        let codec = BitcoinCodec::new(Network::Regtest).with_v2(session);
+        let mut parts = FramedParts::new::<Message>(stream, codec);
+        parts.read_buf = leftover;
+        Framed::from_parts(parts)
+    }
+
+    #[tokio::test]
+    async fn v2_session_roundtrip() {
+        let (mut initiator, mut responder) = tokio::io::duplex(64 * 1024);
+        let peer = tokio::spawn(async move {
+            let (v1, received) = sniff_v1(&mut responder, Network::Regtest).await.unwrap();
+            assert!(!v1);
+            let (session, leftover) =
+                handshake(&mut responder, Network::Regtest, Role::Responder, received)
+                    .await
+                    .unwrap();
+            let session_id = session.session_id();
+            let mut framed = framed(responder, session, leftover);
+            // Echo pings back as pongs, past the rekey interval
+            for _ in 0..300 {
+                let message = framed.next().await.unwrap().unwrap();
+                let Payload::Ping(nonce) = message.payload() else {
+                    panic!("expected ping, got {message:?}");
+                };
+                let pong = Message::new(Network::Regtest, Command::Pong, Payload::Pong(*nonce));
+                framed.send(pong).await.unwrap();
+            }
+            session_id
+        });
+
+        let (mut session, leftover) = handshake(
+            &mut initiator,
+            Network::Regtest,
+            Role::Initiator,
+            BytesMut::new(),
+        )
+        .await
+        .unwrap();
+        let session_id = session.session_id();
+
+        // A decoy first, then the messages, all in one write
+        let mut bytes = BytesMut::new();
+        session.encode_decoy(b"decoy", &mut bytes);
+        let mut framed = framed(initiator, session, leftover);
+        for nonce in 0..300 {
+            framed
+                .codec_mut()
+                .encode(
+                    Message::new(Network::Regtest, Command::Ping, Payload::Ping(nonce)),
+                    &mut bytes,
+                )
+                .unwrap();
+        }
+        framed.get_mut().write_all(&bytes).await.unwrap();
+        for nonce in 0..300 {
+            let message = framed.next().await.unwrap().unwrap();
+            assert_eq!(message.payload(), &Payload::Pong(nonce));
+        }
+        assert_eq!(peer.await.unwrap(), session_id);
+    }
+
+    #[tokio::test]
+    async fn sniffs_v1_version() {
+        let mut bytes = BytesMut::new();
+        Message::new(Network::Regtest, Command::VerAck, Payload::VerAck).encode(&mut bytes);
+        let (v1, received) = sniff_v1(&mut &bytes[..], Network::Regtest).await.unwrap();
+        assert!(!v1);
+        assert_eq!(received, bytes);
+
+        let mut bytes = BytesMut::new();
+        Network::Regtest.magic().encode(&mut bytes);
+        Command::Version.encode(&mut bytes);
+        bytes.put_slice(&[0; 8]);
+        let (v1, received) = sniff_v1(&mut &bytes[..], Network::Regtest).await.unwrap();
+        assert!(v1);
+        assert_eq!(received, bytes);
+    }
+
+    fn ellswift(hex: &str) -> ElligatorSwift {
+        ElligatorSwift::from_array(hex::decode(hex).unwrap().try_into().unwrap())
+    }
+
+    /// Vectors 1 and 2 of the BIP324 `packet_encoding_test_vectors.csv`.
+    #[test]
+    fn bip324_packet_vectors() {
+        let secret_key = SecretKey::from_slice(
+            &hex::decode("61062ea5071d800bbfd59e2e8b53d47d194b095ae5a4df04936b49772ef0d4d7")
+                .unwrap(),
+        )
+        .unwrap();
+        let ours = ellswift("ec0adff257bbfe500c188c80b4fdd640f6b45a482bbc15fc7cef5931deff0aa186f6eb9bba7b85dc4dcc28b28722de1e3d9108b985e2967045668f66098e475b");
+        let theirs = ellswift("a4a94dfce69b4a2a0a099313d10f9f7e7d649d60501c9e1d274c300e0d89aafaffffffffffffffffffffffffffffffffffffffffffffffffffffffff8faf88d5");
+        let secret =
+            ElligatorSwift::shared_secret(ours, theirs, secret_key, ElligatorSwiftParty::A, None);
+        let mut keys = Keys::derive(secret.as_secret_bytes(), Network::Mainnet, Role::Initiator);
+        let mut packet = BytesMut::new();
+        keys.send.encrypt(&[0; 100], &[], false, &mut packet);
+        packet.clear();
+        keys.send.encrypt(&[0x8e], &[], false, &mut packet);
+        assert_eq!(
+            hex::encode(&packet),
+            "7530d2a18720162ac09c25329a60d75adf36eda3c3"
+        );
+
+        let secret_key = SecretKey::from_slice(
+            &hex::decode("6f312890ec83bbb26798abaadd574684a53e74ccef7953b790fcc29409080246")
+                .unwrap(),
+        )
+        .unwrap();
+        let ours = ellswift("a8785af31c029efc82fa9fc677d7118031358d7c6a25b5779a9b900e5ccd94aac97eb36a3c5dbcdb2ca5843cc4c2fe0aaa46d10eb3d233a81c3dde476da00eef");
+        let theirs = ellswift("fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f0000000000000000000000000000000000000000000000000000000000000000");
+        let secret =
+            ElligatorSwift::shared_secret(theirs, ours, secret_key, ElligatorSwiftParty::B, None);
+        let mut keys = Keys::derive(secret.as_secret_bytes(), Network::Mainnet, Role::Responder);
+        assert_eq!(
+            hex::encode(keys.session_id),
+            "b0490e26111cb2d55bbff2ace00f7f644f64006539abb4e7513f05107bb10608"
+        );
+        for _ in 0..999 {
+            keys.send.encrypt(&[], &[], false, &mut packet);
+        }
+        packet.clear();
+        let contents = hex::decode("3eb1d4e98035cfd8eeb29bac969ed3824a").unwrap();
+        keys.send.encrypt(&contents, &[], false, &mut packet);
+        assert_eq!(
+            hex::encode(&packet),
+            "d78adbcba0eebfb15cfbd8142c84dc729d233d0dc11b1d851e46a114122b8d5b96b7d59317"
+        );
+    }
+
+    #[test]
+    fn short_ids_and_tampering() {
+        let keys = |role| Keys::derive(&[7; 32], Network::Regtest, role);
+        let (initiator, responder) = (keys(Role::Initiator), keys(Role::Responder));
+        assert_eq!(initiator.send_terminator, responder.recv_terminator);
+        let session = |keys: Keys| V2Session {
+            send: keys.send,
+            recv: keys.recv,
+            session_id: keys.session_id,
+            recv_aad: Vec::new(),
+            version_received: true,
+            pending_length: None,
+        };
+        let (mut sender, mut receiver) = (session(initiator), session(responder));
+
+        let mut bytes = BytesMut::new();
+        let ping = Message::new(Network::Regtest, Command::Ping, Payload::Ping(1));
+        sender.encode(&ping, &mut bytes);
+        // Length, header, short id, nonce and tag
+        assert_eq!(bytes.len(), 3 + 1 + 1 + 8 + 16);
+        let feefilter = Message::new(
+            Network::Regtest,
+            Command::Unknown(*b"feefilter\0\0\0"),
+            Payload::Raw(vec![0; 8].into()),
+        );
+        sender.encode(&feefilter, &mut bytes);
+        assert_eq!(bytes.len(), 2 * (3 + 1 + 1 + 8 + 16));
+        let limits = Limits::default();
+        assert_eq!(
+            receiver
+                .decode(&mut bytes, Network::Regtest, &limits)
+                .unwrap(),
+            Some(ping)
+        );
+        assert_eq!(
+            receiver
+                .decode(&mut bytes, Network::Regtest, &limits)
+                .unwrap(),
+            Some(feefilter)
+        );
+
+        let pong = Message::new(Network::Regtest, Command::Pong, Payload::Pong(2));
+        sender.encode(&pong, &mut bytes);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(matches!(
+            receiver.decode(&mut bytes, Network::Regtest, &limits),
+            Err(Error::V2Transport(_))
+        ));
+    }
+}
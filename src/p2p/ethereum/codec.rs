@@ -8,7 +8,7 @@ use tokio_util::codec::{Decoder, Encoder};
 
 use super::message::Message;
 
-struct RLPx {
+pub struct RLPx {
     ephemeral_pubk: PublicKey,
     ephemeral_seck: SecretKey,
     initiator_pubk: PublicKey,
@@ -122,8 +122,8 @@ impl Decoder for RLPx {
     type Error = anyhow::Error;
 
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let len = src.len();
-        let message = src.split_to(len);
+        // Nothing is parsed yet, drop whatever arrived
+        src.clear();
 
         Ok(None)
     }
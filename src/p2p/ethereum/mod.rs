@@ -1,2 +1,5 @@
 mod codec;
 mod message;
+
+pub use codec::RLPx;
+pub use message::Message;
@@ -1,2 +1,4 @@
+#[cfg(feature = "bitcoin")]
 pub mod bitcoin;
-// pub mod ethereum;
+#[cfg(feature = "ethereum")]
+pub mod ethereum;